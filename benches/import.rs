@@ -0,0 +1,148 @@
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use chrono::{TimeZone, Utc};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use currentcost::datalog::{parse_log, LogBatches};
+use currentcost::entries::insert_lines;
+use currentcost::impulse::ImpulseReading;
+use currentcost::schema::migrate;
+use currentcost::CurrentCostReading;
+
+const LOG_LINES: u64 = 2_000_000;
+/// How much of the log is inserted into the database each time, as all of
+/// it would take minutes.
+const INSERT_LINES: u64 = 200_000;
+const BATCH_SIZE: usize = 10_000;
+
+/// Writes a data log of `LOG_LINES` lines in the current format, reusing the
+/// one from a previous run if it's there: two sensors reporting every six
+/// seconds, plus a gas meter on an impulse sensor.
+fn generated_log() -> PathBuf {
+    let path = std::env::temp_dir().join(format!("currentcost-bench-{LOG_LINES}.log"));
+    if path.exists() {
+        return path;
+    }
+
+    // written under another name first, so a run that's interrupted doesn't
+    // leave a partial log to be used by the next
+    let partial_path = path.with_extension("log.partial");
+    let start = Utc.with_ymd_and_hms(2019, 8, 20, 15, 40, 42).unwrap();
+    let mut writer = BufWriter::new(File::create(&partial_path).unwrap());
+    for i in 0..LOG_LINES {
+        let timestamp = start + chrono::Duration::seconds(i64::try_from(i / 3 * 6).unwrap());
+        let sensor = i32::try_from(i % 3).unwrap();
+        let line = if sensor == 2 {
+            ImpulseReading {
+                timestamp,
+                device: String::from("CC128-v1.29"),
+                sensor: 9,
+                sensor_type: 2,
+                radio_id: Some(1234),
+                impulses: 89466 + i,
+                impulses_per_unit: 1000,
+                rate: Some(0.6),
+            }
+            .to_log()
+        } else {
+            CurrentCostReading {
+                timestamp,
+                device: String::from("CC128-v1.29"),
+                sensor,
+                temperature: 24.8,
+                power: 479,
+                channels: vec![479],
+                device_time: None,
+                days_since_birth: Some(2353),
+                radio_id: Some(4066),
+                sensor_type: Some(1),
+            }
+            .to_log()
+        };
+        writer.write_all(line.as_bytes()).unwrap();
+    }
+    writer.flush().unwrap();
+    drop(writer);
+    fs::rename(&partial_path, &path).unwrap();
+
+    path
+}
+
+fn parse(c: &mut Criterion) {
+    let path = generated_log();
+
+    let mut group = c.benchmark_group("parse");
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(60));
+    group.throughput(Throughput::Elements(LOG_LINES));
+    group.bench_function("streamed in batches", |b| {
+        b.iter(|| {
+            let file = File::open(&path).unwrap();
+            LogBatches::new(BufReader::new(file), BATCH_SIZE)
+                .map(|batch| batch.unwrap().readings.len())
+                .sum::<usize>()
+        });
+    });
+    group.bench_function("whole file", |b| {
+        b.iter(|| {
+            let contents = fs::read_to_string(&path).unwrap();
+            parse_log(contents.lines().collect()).readings.len()
+        });
+    });
+    group.finish();
+}
+
+/// Reads and inserts the start of the log as `store import` does, into an
+/// empty scratch schema each time, in the database whose connection string
+/// is in `CURRENTCOST_TEST_DATABASE`; skipped if it isn't set.
+fn insert(c: &mut Criterion) {
+    let Ok(connection_string) = std::env::var("CURRENTCOST_TEST_DATABASE") else {
+        eprintln!("Skipping insert, as CURRENTCOST_TEST_DATABASE isn't set");
+        return;
+    };
+    let path = generated_log();
+    let mut client = postgres::Client::connect(&connection_string, postgres::NoTls).unwrap();
+    let schema = format!("bench_{}", std::process::id());
+    client
+        .batch_execute(&format!(
+            "CREATE SCHEMA {schema}; SET search_path TO {schema}"
+        ))
+        .unwrap();
+    migrate(&mut client).unwrap();
+
+    let mut group = c.benchmark_group("insert");
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(60));
+    group.throughput(Throughput::Elements(INSERT_LINES));
+    group.bench_function("streamed in batches", |b| {
+        b.iter_custom(|iterations| {
+            let mut elapsed = Duration::ZERO;
+            for _ in 0..iterations {
+                client
+                    .batch_execute("TRUNCATE entries, temperatures")
+                    .unwrap();
+                let start = Instant::now();
+                let file = File::open(&path).unwrap();
+                let batches = LogBatches::new(BufReader::new(file), BATCH_SIZE)
+                    .take(usize::try_from(INSERT_LINES).unwrap() / BATCH_SIZE);
+                for batch in batches {
+                    insert_lines(&mut client, batch.unwrap().readings).unwrap();
+                }
+                elapsed += start.elapsed();
+            }
+            elapsed
+        });
+    });
+    group.finish();
+
+    client
+        .batch_execute(&format!("DROP SCHEMA {schema} CASCADE"))
+        .unwrap();
+}
+
+criterion_group!(benches, parse, insert);
+criterion_main!(benches);
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS entries (
+    sensor integer NOT NULL,
+    datetime timestamptz NOT NULL,
+    power integer NOT NULL
+);
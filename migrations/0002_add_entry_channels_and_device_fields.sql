@@ -0,0 +1,6 @@
+ALTER TABLE entries
+    ADD COLUMN IF NOT EXISTS channels integer[],
+    ADD COLUMN IF NOT EXISTS radio_id integer,
+    ADD COLUMN IF NOT EXISTS sensor_type integer,
+    ADD COLUMN IF NOT EXISTS days_since_birth integer,
+    ADD COLUMN IF NOT EXISTS device_time time;
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS history (
+    sensor integer NOT NULL,
+    datetime timestamptz NOT NULL,
+    kind text NOT NULL,
+    age integer NOT NULL,
+    kwh real NOT NULL
+);
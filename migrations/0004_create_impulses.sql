@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS impulses (
+    sensor integer NOT NULL,
+    datetime timestamptz NOT NULL,
+    sensor_type integer NOT NULL,
+    radio_id integer,
+    impulses bigint NOT NULL,
+    impulses_per_unit integer NOT NULL,
+    rate double precision
+);
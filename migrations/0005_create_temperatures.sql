@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS temperatures (
+    device text NOT NULL,
+    datetime timestamptz NOT NULL,
+    temperature real NOT NULL
+);
@@ -0,0 +1,23 @@
+ALTER TABLE entries ADD COLUMN IF NOT EXISTS device text NOT NULL DEFAULT '';
+
+-- imports before these keys existed could insert the same reading more than
+-- once, so all but the first copy of each is removed before they're added
+DELETE FROM entries WHERE ctid IN (
+    SELECT ctid FROM (
+        SELECT ctid, row_number() OVER (PARTITION BY device, sensor, datetime ORDER BY ctid) AS copy
+        FROM entries
+    ) AS copies
+    WHERE copy > 1
+);
+DELETE FROM temperatures WHERE ctid IN (
+    SELECT ctid FROM (
+        SELECT ctid, row_number() OVER (PARTITION BY device, datetime ORDER BY ctid) AS copy
+        FROM temperatures
+    ) AS copies
+    WHERE copy > 1
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS entries_device_sensor_datetime_key
+    ON entries (device, sensor, datetime);
+CREATE UNIQUE INDEX IF NOT EXISTS temperatures_device_datetime_key
+    ON temperatures (device, datetime);
@@ -0,0 +1,2 @@
+ALTER TABLE history ADD COLUMN IF NOT EXISTS device text NOT NULL DEFAULT '';
+ALTER TABLE impulses ADD COLUMN IF NOT EXISTS device text NOT NULL DEFAULT '';
@@ -0,0 +1,21 @@
+-- as for entries, all but the first copy of anything imported more than once
+-- is removed before the keys are added
+DELETE FROM impulses WHERE ctid IN (
+    SELECT ctid FROM (
+        SELECT ctid, row_number() OVER (PARTITION BY device, sensor, datetime ORDER BY ctid) AS copy
+        FROM impulses
+    ) AS copies
+    WHERE copy > 1
+);
+DELETE FROM history WHERE ctid IN (
+    SELECT ctid FROM (
+        SELECT ctid, row_number() OVER (PARTITION BY device, sensor, datetime, kind, age ORDER BY ctid) AS copy
+        FROM history
+    ) AS copies
+    WHERE copy > 1
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS impulses_device_sensor_datetime_key
+    ON impulses (device, sensor, datetime);
+CREATE UNIQUE INDEX IF NOT EXISTS history_device_sensor_datetime_kind_age_key
+    ON history (device, sensor, datetime, kind, age);
@@ -0,0 +1,27 @@
+-- a monitor sends the same buckets again every few minutes, each time with
+-- an age counted from when it was sent, so history is keyed by when each
+-- bucket started instead: hourly buckets start on an even hour, daily ones
+-- at midnight and monthly ones on the 1st, all in UTC
+ALTER TABLE history ADD COLUMN IF NOT EXISTS bucket_start timestamptz;
+UPDATE history SET bucket_start = (CASE kind
+    WHEN 'h' THEN date_trunc('hour', datetime AT TIME ZONE 'UTC')
+        - make_interval(hours => extract(hour FROM datetime AT TIME ZONE 'UTC')::integer % 2 + age)
+    WHEN 'd' THEN date_trunc('day', datetime AT TIME ZONE 'UTC') - make_interval(days => age)
+    ELSE date_trunc('month', datetime AT TIME ZONE 'UTC') - make_interval(months => age)
+END) AT TIME ZONE 'UTC';
+ALTER TABLE history ALTER COLUMN bucket_start SET NOT NULL;
+
+-- the copy received last is kept
+DELETE FROM history WHERE ctid IN (
+    SELECT ctid FROM (
+        SELECT ctid, row_number() OVER (
+            PARTITION BY device, sensor, kind, bucket_start ORDER BY datetime DESC, ctid DESC
+        ) AS copy
+        FROM history
+    ) AS copies
+    WHERE copy > 1
+);
+
+DROP INDEX IF EXISTS history_device_sensor_datetime_kind_age_key;
+CREATE UNIQUE INDEX IF NOT EXISTS history_device_sensor_kind_bucket_start_key
+    ON history (device, sensor, kind, bucket_start);
@@ -0,0 +1,187 @@
+//! A record of exactly what a device sent, one line for each read of it
+//! after the time the read returned, e.g.
+//! `2019-08-20T15:40:42.125Z\t<msg><src>CC128-v1.29</src>...</msg>\r\n`,
+//! with any backslash, carriage return or newline in what was read written
+//! as `\\`, `\r` or `\n`. The file is rotated once it reaches
+//! `capture_max_mb` (10) megabytes, keeping `capture_files` (5) old ones.
+//!
+//! `connect --replay` reads a capture back, dating each reading by when it
+//! was first received rather than when it's replayed, so the data log it
+//! writes can be imported again.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+/// Writes each read from a device to a capture file, keeping `files` old ones
+/// as `<path>.1` (the newest) to `<path>.<files>`.
+pub struct Capture {
+    path: PathBuf,
+    max_size: u64,
+    files: usize,
+    file: Option<File>,
+    size: u64,
+}
+
+impl Capture {
+    #[must_use]
+    pub fn new(path: impl Into<PathBuf>, max_size: u64, files: usize) -> Self {
+        Self {
+            path: path.into(),
+            max_size,
+            files,
+            file: None,
+            size: 0,
+        }
+    }
+
+    /// Records `bytes`, exactly as they were read, as received at `received`.
+    pub fn write(&mut self, received: DateTime<Utc>, bytes: &[u8]) {
+        if let Err(err) = self.append(&capture_line(received, bytes)) {
+            log::error!("Couldn't write to {}: {err}", self.path.display());
+            // it's opened again for the next read
+            self.file = None;
+        }
+    }
+
+    fn append(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&self.path)?;
+            self.size = file.metadata()?.len();
+            self.file = Some(file);
+        }
+        let length = line.len() as u64;
+        if self.size > 0 && self.size + length > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.as_mut().expect("opened above").write_all(line)?;
+        self.size += length;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        for number in (1..self.files).rev() {
+            rename_if_exists(&self.rotated_path(number), &self.rotated_path(number + 1))?;
+        }
+        rename_if_exists(&self.path, &self.rotated_path(1))?;
+
+        self.file = Some(File::create(&self.path)?);
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated_path(&self, number: usize) -> PathBuf {
+        let mut path = OsString::from(&self.path);
+        path.push(format!(".{number}"));
+        PathBuf::from(path)
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn capture_line(received: DateTime<Utc>, bytes: &[u8]) -> Vec<u8> {
+    let mut line = received
+        .to_rfc3339_opts(SecondsFormat::Millis, true)
+        .into_bytes();
+    line.push(b'\t');
+    for &byte in bytes {
+        match byte {
+            b'\\' => line.extend_from_slice(b"\\\\"),
+            b'\r' => line.extend_from_slice(b"\\r"),
+            b'\n' => line.extend_from_slice(b"\\n"),
+            _ => line.push(byte),
+        }
+    }
+    line.push(b'\n');
+    line
+}
+
+/// The time a line of a capture file was received and the bytes that were
+/// read then, or `None` if it isn't a line of a capture file.
+#[must_use]
+pub fn parse_capture_line(line: &[u8]) -> Option<(DateTime<Utc>, Vec<u8>)> {
+    let tab = line.iter().position(|&byte| byte == b'\t')?;
+    let received = std::str::from_utf8(&line[..tab]).ok()?;
+    let received = DateTime::parse_from_rfc3339(received).ok()?;
+
+    let escaped = &line[tab + 1..];
+    let escaped = escaped.strip_suffix(b"\n").unwrap_or(escaped);
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut escaped = escaped.iter();
+    while let Some(&byte) = escaped.next() {
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        match escaped.next() {
+            Some(b'r') => bytes.push(b'\r'),
+            Some(b'n') => bytes.push(b'\n'),
+            Some(&other) => bytes.push(other),
+            None => bytes.push(b'\\'),
+        }
+    }
+    Some((received.with_timezone(&Utc), bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capture::{parse_capture_line, Capture};
+    use chrono::prelude::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn reads_are_captured_byte_for_byte() {
+        let received = Utc.with_ymd_and_hms(2019, 8, 20, 15, 40, 42).unwrap();
+        // the end of one message and the start of the next, as one read
+        let bytes = b"\xff</msg>\r\n<msg>\\\t\r";
+        let line = super::capture_line(received, bytes);
+        assert_eq!(
+            b"2019-08-20T15:40:42.000Z\t\xff</msg>\\r\\n<msg>\\\\\t\\r\n".to_vec(),
+            line
+        );
+
+        let (parsed_received, parsed_bytes) = parse_capture_line(&line).unwrap();
+        assert_eq!(received, parsed_received);
+        assert_eq!(bytes.to_vec(), parsed_bytes);
+        assert_eq!(
+            None,
+            parse_capture_line(b"<msg><src>CC128-v1.29</src></msg>\n")
+        );
+    }
+
+    #[test]
+    fn capture_gets_rotated() {
+        let dir = env::temp_dir().join("currentcost-capture-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("capture.log");
+        let received = Utc.with_ymd_and_hms(2019, 8, 20, 15, 40, 42).unwrap();
+
+        // each line is 38 bytes, so two fit in each file
+        let mut capture = Capture::new(&path, 80, 2);
+        for number in 0..7 {
+            capture.write(received, format!("<msg>{number}</msg>").as_bytes());
+        }
+
+        let contents = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert!(contents("capture.log").ends_with("<msg>6</msg>\n"));
+        assert!(contents("capture.log.1").ends_with("<msg>5</msg>\n"));
+        assert!(contents("capture.log.2").ends_with("<msg>3</msg>\n"));
+        assert!(!dir.join("capture.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1076 @@
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use postgres::config::SslMode;
+use toml::{Table, Value};
+
+use crate::protocol::Dialect;
+
+/// The bit rates a serial port can be set to; the Classic talks at 9600 and
+/// the CC128 and EnviR at 57600.
+const BIT_RATES: [u32; 9] = [
+    1200, 2400, 4800, 9600, 19200, 38400, 57600, 115_200, 230_400,
+];
+
+/// Everything wrong with a config file, so it can all be fixed in one go.
+#[derive(Debug)]
+pub struct ConfigError {
+    /// `None` if there was no config file, only the environment.
+    pub path: Option<PathBuf>,
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Problems with {}:", describe(self.path.as_deref()))?;
+        for problem in &self.problems {
+            write!(f, "\n  {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The sections of the config file, which `CURRENTCOST_<SECTION>_<KEY>`
+/// environment variables can set keys in.
+const SECTIONS: [&str; 4] = ["database", "serial", "network", "logging"];
+
+/// How `find` picks a config file, for both binaries' `--config --help`.
+pub const CONFIG_HELP: &str =
+    "Config file to use instead of $CURRENTCOST_CONFIG or the first found of \
+$XDG_CONFIG_HOME/currentcost/config.toml (~/.config if it isn't set), \
+/etc/currentcost/config.toml, and config.toml next to the binary or in the current directory; \
+without any, CURRENTCOST_* variables are used alone";
+
+/// The config file to use, as `CONFIG_HELP` describes: `path` if one was
+/// given, then `$CURRENTCOST_CONFIG`, then the first of `search_path` that
+/// exists, or `None` if there isn't one.
+#[must_use]
+pub fn find(path: Option<&Path>) -> Option<PathBuf> {
+    if let Some(path) = path {
+        return Some(path.to_path_buf());
+    }
+    if let Some(path) = env::var_os("CURRENTCOST_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+
+    search_path(|name| env::var_os(name))
+        .into_iter()
+        .find(|path| path.is_file())
+}
+
+/// Where the config was read from, for messages about it.
+#[must_use]
+pub fn describe(path: Option<&Path>) -> String {
+    match path {
+        Some(path) => path.display().to_string(),
+        None => String::from("CURRENTCOST_* environment variables (no config file was found)"),
+    }
+}
+
+/// Where to look for a config file, in order: `$XDG_CONFIG_HOME` (or
+/// `~/.config`), `/etc`, next to the binary, then the current directory.
+fn search_path(var: impl Fn(&str) -> Option<OsString>) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    let config_home = var("XDG_CONFIG_HOME")
+        .filter(|config_home| !config_home.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| var("HOME").map(|home| Path::new(&home).join(".config")));
+    if let Some(config_home) = config_home {
+        paths.push(config_home.join("currentcost").join("config.toml"));
+    }
+    paths.push(PathBuf::from("/etc/currentcost/config.toml"));
+    paths.push(get_path_to_bin_location().join("config.toml"));
+    paths.push(PathBuf::from("config.toml"));
+
+    paths
+}
+
+fn get_path_to_bin_location() -> PathBuf {
+    let path = env::current_exe().unwrap_or_default();
+
+    path.parent()
+        .map_or_else(|| PathBuf::from("."), Path::to_path_buf)
+}
+
+/// The config file at `path`, or an empty one if there isn't a file, with
+/// the environment applied over it.
+fn read_table(path: Option<&Path>) -> Result<Table, ConfigError> {
+    let mut table = match path {
+        Some(path) => {
+            let error = |problem| ConfigError {
+                path: Some(path.to_path_buf()),
+                problems: vec![problem],
+            };
+            let properties = fs::read_to_string(path)
+                .map_err(|err| error(format!("Couldn't read it: {err}")))?;
+            properties
+                .parse::<Table>()
+                .map_err(|err| error(format!("It isn't valid TOML: {err}")))?
+        }
+        None => Table::new(),
+    };
+    apply_environment(&mut table, env::vars());
+    Ok(table)
+}
+
+/// Sets each key named by a `CURRENTCOST_<SECTION>_<KEY>` variable, e.g.
+/// `CURRENTCOST_SERIAL_PORT`, over whatever the config file has for it.
+fn apply_environment(table: &mut Table, vars: impl Iterator<Item = (String, String)>) {
+    for (name, value) in vars {
+        let Some(name) = name.strip_prefix("CURRENTCOST_") else {
+            continue;
+        };
+        for section in SECTIONS {
+            let Some(key) = name
+                .strip_prefix(&section.to_uppercase())
+                .and_then(|key| key.strip_prefix('_'))
+            else {
+                continue;
+            };
+            let section = table
+                .entry(section)
+                .or_insert_with(|| Value::Table(Table::new()));
+            // a section that isn't a table gets reported when it's read
+            if let Value::Table(section) = section {
+                section.insert(key.to_lowercase(), Value::String(value.clone()));
+            }
+        }
+    }
+}
+
+/// The config `store` needs.
+pub struct Config {
+    pub database: DatabaseConfig,
+}
+
+impl Config {
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        Self::from_table(&read_table(path)?).map_err(|problems| ConfigError {
+            path: path.map(Path::to_path_buf),
+            problems,
+        })
+    }
+
+    pub fn from_table(table: &Table) -> Result<Self, Vec<String>> {
+        let mut problems = Vec::new();
+        let database = DatabaseConfig::new(&mut Section::new(table, "database", &mut problems));
+
+        if problems.is_empty() {
+            Ok(Self { database })
+        } else {
+            Err(problems)
+        }
+    }
+}
+
+pub struct DatabaseConfig {
+    ignore_db: bool,
+    pub(crate) postgres: postgres::Config,
+    /// Trusted as well as the system's certificates when connecting with TLS.
+    pub(crate) root_certificate: Option<native_tls::Certificate>,
+    batch_size: usize,
+}
+
+// leaves out the root certificate, which isn't Debug
+impl fmt::Debug for DatabaseConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DatabaseConfig")
+            .field("ignore_db", &self.ignore_db)
+            .field("postgres", &self.postgres)
+            .field("batch_size", &self.batch_size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DatabaseConfig {
+    fn new(section: &mut Section) -> Self {
+        #![allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
+        let ignore_db = section.boolean("ignore_db", false);
+        let connection_string = section.string("connection_string");
+        let mut postgres = match connection_string.as_deref().map(str::parse) {
+            Some(Ok(postgres)) => postgres,
+            Some(Err(err)) => {
+                section.problem("connection_string", format_args!("isn't valid: {err}"));
+                postgres::Config::new()
+            }
+            None => postgres::Config::new(),
+        };
+
+        // the connection details aren't needed if nothing goes in the database,
+        // and come from the connection string if there is one
+        let from_connection_string = connection_string.is_some();
+        let setting = |section: &mut Section, key| {
+            if from_connection_string {
+                if section.get(key).is_some() {
+                    section.problem(key, "can't be used with connection_string");
+                }
+                None
+            } else if ignore_db {
+                section.string(key)
+            } else {
+                Some(section.required_string(key))
+            }
+        };
+        if let Some(database_name) = setting(section, "db_name") {
+            postgres.dbname(&database_name);
+        }
+        if let Some(host) = setting(section, "hostname") {
+            postgres.host(&host);
+        }
+        if let Some(user) = setting(section, "user") {
+            postgres.user(&user);
+        }
+        if section.get("port").is_some() {
+            if from_connection_string {
+                section.problem("port", "can't be used with connection_string");
+            } else {
+                postgres.port(section.integer("port", 5432, 1..=65535) as u16);
+            }
+        }
+
+        // so an unreachable server can't hold things up for long, unless the
+        // connection string already says how long to wait
+        if section.get("connect_timeout").is_some() || postgres.get_connect_timeout().is_none() {
+            let connect_timeout = section.integer("connect_timeout", 10, 1..=3600) as u64;
+            postgres.connect_timeout(Duration::from_secs(connect_timeout));
+        }
+
+        if let Some(password) = Self::password(section) {
+            postgres.password(password);
+        }
+        match section.string("ssl_mode").as_deref() {
+            Some("disable") => {
+                postgres.ssl_mode(SslMode::Disable);
+            }
+            Some("prefer") => {
+                postgres.ssl_mode(SslMode::Prefer);
+            }
+            Some("require") => {
+                postgres.ssl_mode(SslMode::Require);
+            }
+            Some(ssl_mode) => section.problem(
+                "ssl_mode",
+                format_args!("should be disable, prefer or require, not {ssl_mode:?}"),
+            ),
+            // without a connection string to say otherwise, don't use TLS
+            None if !from_connection_string => {
+                postgres.ssl_mode(SslMode::Disable);
+            }
+            None => (),
+        }
+        let root_certificate = section.string("ssl_root_cert").and_then(|path| {
+            let certificate = fs::read(&path)
+                .map_err(|err| format!("couldn't be read: {err}"))
+                .and_then(|pem| {
+                    native_tls::Certificate::from_pem(&pem)
+                        .map_err(|err| format!("isn't a PEM certificate: {err}"))
+                });
+            certificate
+                .map_err(|message| section.problem("ssl_root_cert", message))
+                .ok()
+        });
+        // how many lines of the data log are read and inserted at a time
+        let batch_size = section.integer("batch_size", 10_000, 1..=1_000_000) as usize;
+
+        Self {
+            ignore_db,
+            postgres,
+            root_certificate,
+            batch_size,
+        }
+    }
+
+    /// The password given in the config file, or read from `password_file` so
+    /// it needn't be kept in the config file.
+    fn password(section: &mut Section) -> Option<String> {
+        match (section.string("password"), section.string("password_file")) {
+            (Some(_), Some(_)) => {
+                section.problem("password_file", "can't be used with password");
+                None
+            }
+            (Some(password), None) => Some(password),
+            (None, Some(path)) => match fs::read_to_string(&path) {
+                Ok(password) => Some(password.trim_end_matches(&['\r', '\n'][..]).to_owned()),
+                Err(err) => {
+                    section.problem("password_file", format_args!("couldn't be read: {err}"));
+                    None
+                }
+            },
+            (None, None) => None,
+        }
+    }
+
+    #[must_use]
+    pub fn use_database(&self) -> bool {
+        !self.ignore_db
+    }
+
+    #[must_use]
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+}
+
+/// Values given on `connect`'s command line, which take the place of the
+/// ones in the config file.
+#[derive(Default)]
+pub struct ConnectOverrides {
+    /// Set when reading from somewhere other than the serial port, so the
+    /// config file needn't say which port it is.
+    pub without_serial: bool,
+    pub port: Option<String>,
+    /// A `host:port` to read from instead of the `[network]` address.
+    pub network_address: Option<String>,
+    pub bit_rate: Option<u32>,
+    pub data_log_path: Option<String>,
+    pub debug_log_path: Option<String>,
+    pub capture_log_path: Option<String>,
+    pub log_level: Option<log::LevelFilter>,
+}
+
+/// The config `connect` needs.
+#[derive(Debug)]
+pub struct ConnectConfig {
+    /// Empty if the port is found by `usb_device` or reached over `network`
+    /// instead.
+    pub port: String,
+    pub usb_device: Option<UsbDevice>,
+    pub network: Option<NetworkConfig>,
+    pub bit_rate: u32,
+    pub timeout: u32,
+    pub dialect: Option<Dialect>,
+    pub data_log_path: String,
+    pub history_log_path: Option<String>,
+    pub debug_log_path: String,
+    /// Where everything the device sends is recorded exactly, if anywhere.
+    pub capture_log_path: Option<String>,
+    /// How big the capture file gets before it's rotated.
+    pub capture_max_size: u64,
+    /// How many rotated capture files are kept.
+    pub capture_files: usize,
+    /// Overrides the default levels of debug for stdout and info for the debug log.
+    pub log_level: Option<log::LevelFilter>,
+    pub rejected_line_level: log::Level,
+    /// Where readings also get inserted as they arrive, if `live` is set.
+    pub database: Option<DatabaseConfig>,
+    /// Where readings are kept while the database can't be reached.
+    pub database_buffer_path: String,
+}
+
+impl ConnectConfig {
+    pub fn load(path: Option<&Path>, overrides: &ConnectOverrides) -> Result<Self, ConfigError> {
+        Self::from_table(&read_table(path)?, overrides).map_err(|problems| ConfigError {
+            path: path.map(Path::to_path_buf),
+            problems,
+        })
+    }
+
+    pub fn from_table(table: &Table, overrides: &ConnectOverrides) -> Result<Self, Vec<String>> {
+        #![allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
+        let mut problems = Vec::new();
+
+        // a port given on the command line wins over a bridge to connect to
+        let network_configured = overrides.port.is_none()
+            && (overrides.network_address.is_some() || table.contains_key("network"));
+
+        let mut serial = Section::new(table, "serial", &mut problems);
+        // and over a USB device to look for
+        let usb_device = match overrides.port {
+            Some(_) => None,
+            None => UsbDevice::new(&mut serial),
+        };
+        let port = overrides.port.clone().unwrap_or_else(|| {
+            if overrides.without_serial || network_configured || serial.get("usb_id").is_some() {
+                serial.string("port").unwrap_or_default()
+            } else {
+                serial.required_string("port")
+            }
+        });
+        let bit_rate = overrides
+            .bit_rate
+            .unwrap_or_else(|| serial.integer("bit_rate", 57600, 1..=i64::from(u32::MAX)) as u32);
+        if !BIT_RATES.contains(&bit_rate) {
+            serial.problem("bit_rate", format_args!("should be one of {BIT_RATES:?}"));
+        }
+        let timeout = serial.integer("timeout", 5, 1..=3600) as u32;
+        // the dialect is worked out from each message unless one is forced
+        let dialect = match serial.string("dialect").as_deref() {
+            None | Some("auto") => None,
+            Some(_) => serial.parsed("dialect", "auto, classic, cc128 or envir"),
+        };
+
+        let mut logging = Section::new(table, "logging", &mut problems);
+        let data_log_dir = logging
+            .string("data_log_output_dir")
+            .unwrap_or_else(|| String::from("."));
+        let data_log_path = overrides
+            .data_log_path
+            .clone()
+            .unwrap_or_else(|| join_path(&data_log_dir, &logging.required_string("data_log")));
+        logging.check_directory_exists("data_log", &data_log_path);
+        // history is only kept if a file has been configured for it
+        let history_log_path = logging
+            .string("history_log")
+            .map(|history_log| join_path(&data_log_dir, &history_log));
+        if let Some(history_log_path) = &history_log_path {
+            logging.check_directory_exists("history_log", history_log_path);
+        }
+        // as is a capture of exactly what the device sends
+        let capture_log_path = overrides.capture_log_path.clone().or_else(|| {
+            logging
+                .string("capture_log")
+                .map(|capture_log| join_path(&data_log_dir, &capture_log))
+        });
+        if let Some(capture_log_path) = &capture_log_path {
+            logging.check_directory_exists("capture_log", capture_log_path);
+        }
+        let capture_max_size =
+            logging.integer("capture_max_mb", 10, 1..=100_000) as u64 * 1_000_000;
+        let capture_files = logging.integer("capture_files", 5, 1..=1000) as usize;
+        let debug_log_path = overrides.debug_log_path.clone().unwrap_or_else(|| {
+            let debug_log_dir = logging
+                .string("connect_debug_log_location")
+                .unwrap_or_else(|| String::from("."));
+            join_path(
+                &debug_log_dir,
+                &logging.required_string("connect_debug_log"),
+            )
+        });
+        logging.check_directory_exists("connect_debug_log", &debug_log_path);
+        let level_names = "error, warn, info, debug or trace";
+        let log_level = overrides
+            .log_level
+            .or_else(|| logging.parsed("log_level", level_names));
+        // lines the device sends that can't be parsed are logged at this level
+        let rejected_line_level = logging
+            .parsed("rejected_line_level", level_names)
+            .unwrap_or(log::Level::Info);
+        let database_buffer_path = join_path(
+            &data_log_dir,
+            &logging
+                .string("database_buffer")
+                .unwrap_or_else(|| String::from("database-buffer.log")),
+        );
+
+        // readings only go straight into the database if asked to
+        let mut database = Section::new(table, "database", &mut problems);
+        let database = if database.boolean("live", false) {
+            let config = DatabaseConfig::new(&mut database);
+            if !config.use_database() {
+                database.problem("live", "can't be used with ignore_db");
+            }
+            Some(config)
+        } else {
+            None
+        };
+        if database.is_some() {
+            Section::new(table, "logging", &mut problems)
+                .check_directory_exists("database_buffer", &database_buffer_path);
+        }
+
+        let network = if network_configured {
+            let mut network = Section::new(table, "network", &mut problems);
+            Some(NetworkConfig::new(
+                &mut network,
+                overrides.network_address.clone(),
+            ))
+        } else {
+            None
+        };
+
+        if problems.is_empty() {
+            Ok(Self {
+                port,
+                usb_device,
+                network,
+                bit_rate,
+                timeout,
+                dialect,
+                data_log_path,
+                history_log_path,
+                debug_log_path,
+                capture_log_path,
+                capture_max_size,
+                capture_files,
+                log_level,
+                rejected_line_level,
+                database,
+                database_buffer_path,
+            })
+        } else {
+            Err(problems)
+        }
+    }
+}
+
+/// A USB serial adapter to find by its IDs, as listed by `connect
+/// --list-ports`, wherever it turns up after being plugged in again.
+#[derive(Debug, PartialEq)]
+pub struct UsbDevice {
+    pub vid: u16,
+    pub pid: u16,
+    /// Tells apart adapters with the same IDs, if there's more than one.
+    pub serial_number: Option<String>,
+}
+
+impl UsbDevice {
+    fn new(section: &mut Section) -> Option<Self> {
+        let serial_number = section.string("usb_serial");
+        let Some(id) = section.string("usb_id") else {
+            if serial_number.is_some() {
+                section.problem("usb_serial", "needs usb_id as well");
+            }
+            return None;
+        };
+
+        let ids = id.split_once(':').and_then(|(vid, pid)| {
+            Some((
+                u16::from_str_radix(vid, 16).ok()?,
+                u16::from_str_radix(pid, 16).ok()?,
+            ))
+        });
+        let Some((vid, pid)) = ids else {
+            let message = format!("should be a vendor and product ID like 0403:6001, not {id:?}");
+            section.problem("usb_id", message);
+            return None;
+        };
+        Some(Self {
+            vid,
+            pid,
+            serial_number,
+        })
+    }
+
+    #[must_use]
+    pub fn matches(&self, port: &serialport::UsbPortInfo) -> bool {
+        port.vid == self.vid
+            && port.pid == self.pid
+            && (self.serial_number.is_none() || self.serial_number == port.serial_number)
+    }
+}
+
+/// A serial port on another machine, shared over the network by a bridge
+/// such as ser2net.
+#[derive(Debug, PartialEq)]
+pub struct NetworkConfig {
+    /// The bridge's `host:port`.
+    pub address: String,
+    /// Whether the bridge speaks RFC 2217, which lets the port's bit rate be
+    /// set from `[serial]`, rather than passing the bytes straight through.
+    pub rfc2217: bool,
+    /// How many seconds can pass without anything arriving before the
+    /// connection is taken to have died and is made again; the device sends
+    /// something every six seconds.
+    pub idle_timeout: u32,
+}
+
+impl NetworkConfig {
+    fn new(section: &mut Section, address: Option<String>) -> Self {
+        #![allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
+        let address = address.unwrap_or_else(|| section.required_string("address"));
+        let valid = address.rsplit_once(':').is_some_and(|(host, port)| {
+            !host.is_empty() && port.parse::<u16>().is_ok_and(|port| port > 0)
+        });
+        if !address.is_empty() && !valid {
+            let message =
+                format!("should be a host and port like ser2net.local:3001, not {address:?}");
+            section.problem("address", message);
+        }
+        let rfc2217 = section.boolean("rfc2217", false);
+        let idle_timeout = section.integer("idle_timeout", 60, 1..=3600) as u32;
+        Self {
+            address,
+            rfc2217,
+            idle_timeout,
+        }
+    }
+}
+
+fn join_path(dir: &str, file: &str) -> String {
+    Path::new(dir).join(file).to_string_lossy().into_owned()
+}
+
+/// Reads the keys of one section of the config file, noting each one that's
+/// missing or invalid.
+struct Section<'a> {
+    name: &'static str,
+    table: Option<&'a Table>,
+    problems: &'a mut Vec<String>,
+}
+
+impl<'a> Section<'a> {
+    fn new(config: &'a Table, name: &'static str, problems: &'a mut Vec<String>) -> Self {
+        let table = match config.get(name) {
+            Some(Value::Table(table)) => Some(table),
+            Some(_) => {
+                problems.push(format!("[{name}] should be a table"));
+                None
+            }
+            None => None,
+        };
+
+        Self {
+            name,
+            table,
+            problems,
+        }
+    }
+
+    fn problem(&mut self, key: &str, message: impl fmt::Display) {
+        self.problems
+            .push(format!("[{}] {key} {message}", self.name));
+    }
+
+    fn get(&self, key: &str) -> Option<&'a Value> {
+        self.table.and_then(|table| table.get(key))
+    }
+
+    fn string(&mut self, key: &str) -> Option<String> {
+        match self.get(key)? {
+            Value::String(value) => Some(value.clone()),
+            _ => {
+                self.problem(key, "should be a string");
+                None
+            }
+        }
+    }
+
+    fn required_string(&mut self, key: &str) -> String {
+        if self.get(key).is_none() {
+            self.problem(key, "is missing");
+        }
+        self.string(key).unwrap_or_default()
+    }
+
+    // values set from the environment are always strings, so they get parsed
+    fn integer(&mut self, key: &str, default: i64, range: RangeInclusive<i64>) -> i64 {
+        let value = match self.get(key) {
+            None => return default,
+            Some(Value::Integer(value)) => Some(*value),
+            Some(Value::String(value)) => value.parse().ok(),
+            Some(_) => None,
+        };
+        match value {
+            Some(value) if range.contains(&value) => value,
+            Some(_) => {
+                let message = format!("should be between {} and {}", range.start(), range.end());
+                self.problem(key, message);
+                default
+            }
+            None => {
+                self.problem(key, "should be an integer");
+                default
+            }
+        }
+    }
+
+    fn boolean(&mut self, key: &str, default: bool) -> bool {
+        match self.get(key) {
+            None => default,
+            Some(Value::Boolean(value)) => *value,
+            Some(Value::String(value)) if value == "true" || value == "false" => value == "true",
+            Some(_) => {
+                self.problem(key, "should be true or false");
+                default
+            }
+        }
+    }
+
+    fn parsed<T: FromStr>(&mut self, key: &str, expected: &str) -> Option<T> {
+        let value = self.string(key)?;
+        let parsed = value.parse().ok();
+        if parsed.is_none() {
+            self.problem(key, format_args!("should be {expected}, not {value:?}"));
+        }
+        parsed
+    }
+
+    fn check_directory_exists(&mut self, key: &str, path: &str) {
+        let directory = Path::new(path)
+            .parent()
+            .filter(|directory| !directory.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        if !directory.is_dir() {
+            let message = format!("is in {}, which isn't a directory", directory.display());
+            self.problem(key, message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{apply_environment, search_path, Config, ConnectConfig, ConnectOverrides};
+    use crate::protocol::Dialect;
+    use postgres::config::{Host, SslMode};
+    use std::env;
+    use std::ffi::OsString;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use toml::Table;
+
+    fn connect_config(logging: &str) -> String {
+        let dir = env::temp_dir();
+        format!(
+            "[serial]
+            port = \"/dev/ttyUSB1\"
+            dialect = \"envir\"
+
+            [logging]
+            data_log_output_dir = {dir:?}
+            connect_debug_log_location = {dir:?}
+            {logging}"
+        )
+    }
+
+    #[test]
+    fn defaults_fill_in_optional_keys() {
+        let table = connect_config("data_log = \"data.log\"\nconnect_debug_log = \"debug.log\"")
+            .parse()
+            .unwrap();
+        let config = ConnectConfig::from_table(&table, &ConnectOverrides::default()).unwrap();
+
+        assert_eq!("/dev/ttyUSB1", config.port);
+        assert_eq!(57600, config.bit_rate);
+        assert_eq!(5, config.timeout);
+        assert_eq!(Some(Dialect::Envir), config.dialect);
+        assert!(config.data_log_path.ends_with("data.log"));
+        assert_eq!(None, config.history_log_path);
+        assert_eq!(None, config.capture_log_path);
+        assert_eq!(10_000_000, config.capture_max_size);
+        assert_eq!(None, config.log_level);
+        assert_eq!(log::Level::Info, config.rejected_line_level);
+        assert!(config.database.is_none());
+    }
+
+    #[test]
+    fn port_can_be_found_by_usb_id() {
+        let logging = "data_log = \"data.log\"\nconnect_debug_log = \"debug.log\"";
+        let table = connect_config(logging)
+            .replace(
+                "port = \"/dev/ttyUSB1\"",
+                "usb_id = \"0403:6001\"\nusb_serial = \"A600e2Ax\"",
+            )
+            .parse()
+            .unwrap();
+        let config = ConnectConfig::from_table(&table, &ConnectOverrides::default()).unwrap();
+        let usb_device = config.usb_device.unwrap();
+        let mut port = serialport::UsbPortInfo {
+            vid: 0x0403,
+            pid: 0x6001,
+            serial_number: Some(String::from("A600e2Ax")),
+            manufacturer: None,
+            product: None,
+        };
+        assert!(usb_device.matches(&port));
+        port.serial_number = Some(String::from("A600e2Ay"));
+        assert!(!usb_device.matches(&port));
+
+        let table = "[serial]\nusb_id = \"ftdi\"\n[logging]\ndata_log = \"data.log\"\nconnect_debug_log = \"debug.log\""
+            .parse()
+            .unwrap();
+        let problems = ConnectConfig::from_table(&table, &ConnectOverrides::default()).unwrap_err();
+        assert_eq!(
+            vec!["[serial] usb_id should be a vendor and product ID like 0403:6001, not \"ftdi\""],
+            problems
+        );
+    }
+
+    #[test]
+    fn serial_port_can_be_on_the_network() {
+        let logging = "data_log = \"data.log\"\nconnect_debug_log = \"debug.log\"";
+        let table = format!(
+            "{}\n[network]\naddress = \"ser2net.local:3001\"\nrfc2217 = true",
+            connect_config(logging).replace("port = \"/dev/ttyUSB1\"", "")
+        )
+        .parse()
+        .unwrap();
+        let config = ConnectConfig::from_table(&table, &ConnectOverrides::default()).unwrap();
+        assert_eq!("", config.port);
+        let network = config.network.unwrap();
+        assert_eq!("ser2net.local:3001", network.address);
+        assert!(network.rfc2217);
+        assert_eq!(60, network.idle_timeout);
+
+        // an address on the command line needs no [network] section
+        let table = connect_config(logging).parse().unwrap();
+        let overrides = ConnectOverrides {
+            network_address: Some(String::from("192.168.1.20:3001")),
+            ..ConnectOverrides::default()
+        };
+        let network = ConnectConfig::from_table(&table, &overrides)
+            .unwrap()
+            .network
+            .unwrap();
+        assert_eq!("192.168.1.20:3001", network.address);
+        assert!(!network.rfc2217);
+
+        let table = format!(
+            "{}\n[network]\naddress = \"ser2net.local\"\nrfc2217 = \"yes\"",
+            connect_config(logging)
+        )
+        .parse()
+        .unwrap();
+        let problems = ConnectConfig::from_table(&table, &ConnectOverrides::default()).unwrap_err();
+        assert_eq!(
+            vec![
+                "[network] address should be a host and port like ser2net.local:3001, not \"ser2net.local\"",
+                "[network] rfc2217 should be true or false",
+            ],
+            problems
+        );
+    }
+
+    #[test]
+    fn live_database_needs_its_connection_details() {
+        let logging = "data_log = \"data.log\"\nconnect_debug_log = \"debug.log\"";
+        let table = format!("{}\n[database]\nlive = true", connect_config(logging))
+            .parse()
+            .unwrap();
+        let problems = ConnectConfig::from_table(&table, &ConnectOverrides::default()).unwrap_err();
+        assert_eq!(
+            vec![
+                "[database] db_name is missing",
+                "[database] hostname is missing",
+                "[database] user is missing",
+            ],
+            problems
+        );
+
+        let table = format!(
+            "{}\ndatabase_buffer = \"buffer.log\"\n[database]\nlive = true\nconnection_string = \"host=/tmp\"",
+            connect_config(logging)
+        )
+        .parse()
+        .unwrap();
+        let config = ConnectConfig::from_table(&table, &ConnectOverrides::default()).unwrap();
+        assert!(config.database.is_some());
+        assert!(config.database_buffer_path.ends_with("buffer.log"));
+
+        let table = format!(
+            "{}\n[database]\nlive = true\nignore_db = true",
+            connect_config(logging)
+        )
+        .parse()
+        .unwrap();
+        let problems = ConnectConfig::from_table(&table, &ConnectOverrides::default()).unwrap_err();
+        assert_eq!(
+            vec!["[database] live can't be used with ignore_db"],
+            problems
+        );
+    }
+
+    #[test]
+    fn every_problem_gets_reported() {
+        let table = "[serial]
+        bit_rate = 1234
+        timeout = \"soon\"
+        dialect = \"cc256\"
+
+        [logging]
+        data_log_output_dir = \"/no/such/directory\"
+        data_log = \"data.log\"
+        rejected_line_level = \"loud\""
+            .parse()
+            .unwrap();
+        let problems = ConnectConfig::from_table(&table, &ConnectOverrides::default()).unwrap_err();
+
+        assert_eq!(
+            vec![
+                "[serial] port is missing",
+                "[serial] bit_rate should be one of [1200, 2400, 4800, 9600, 19200, 38400, 57600, 115200, 230400]",
+                "[serial] timeout should be an integer",
+                "[serial] dialect should be auto, classic, cc128 or envir, not \"cc256\"",
+                "[logging] data_log is in /no/such/directory, which isn't a directory",
+                "[logging] connect_debug_log is missing",
+                "[logging] rejected_line_level should be error, warn, info, debug or trace, not \"loud\"",
+            ],
+            problems
+        );
+    }
+
+    #[test]
+    fn overrides_take_the_place_of_the_config_file() {
+        let table = connect_config("").parse().unwrap();
+        let data_log = env::temp_dir().join("data.log");
+        let overrides = ConnectOverrides {
+            without_serial: false,
+            port: Some(String::from("/dev/ttyUSB0")),
+            network_address: None,
+            bit_rate: Some(9600),
+            data_log_path: Some(data_log.to_string_lossy().into_owned()),
+            debug_log_path: Some(String::from("debug.log")),
+            capture_log_path: None,
+            log_level: Some(log::LevelFilter::Warn),
+        };
+        let config = ConnectConfig::from_table(&table, &overrides).unwrap();
+
+        assert_eq!("/dev/ttyUSB0", config.port);
+        assert_eq!(9600, config.bit_rate);
+        assert_eq!(data_log.to_str().unwrap(), config.data_log_path);
+        assert_eq!("debug.log", config.debug_log_path);
+        assert_eq!(Some(log::LevelFilter::Warn), config.log_level);
+
+        // the port isn't needed when reading from somewhere else
+        let table = connect_config("")
+            .replace("port = \"/dev/ttyUSB1\"", "")
+            .parse()
+            .unwrap();
+        let overrides = ConnectOverrides {
+            without_serial: true,
+            data_log_path: Some(data_log.to_string_lossy().into_owned()),
+            debug_log_path: Some(String::from("debug.log")),
+            ..ConnectOverrides::default()
+        };
+        let config = ConnectConfig::from_table(&table, &overrides).unwrap();
+        assert_eq!("", config.port);
+    }
+
+    #[test]
+    fn database_details_are_only_needed_if_it_is_used() {
+        let table = "[database]\nignore_db = true".parse().unwrap();
+        let config = Config::from_table(&table).unwrap();
+        assert!(!config.database.use_database());
+        assert_eq!(10_000, config.database.batch_size());
+
+        let table = "[database]\nhostname = 5\nbatch_size = 0".parse().unwrap();
+        let problems = Config::from_table(&table).err().unwrap();
+        assert_eq!(
+            vec![
+                "[database] db_name is missing",
+                "[database] hostname should be a string",
+                "[database] user is missing",
+                "[database] batch_size should be between 1 and 1000000",
+            ],
+            problems
+        );
+    }
+
+    #[test]
+    fn config_file_gets_searched_for_in_order() {
+        let paths = search_path(|name| match name {
+            "XDG_CONFIG_HOME" => Some(OsString::from("/home/tolien/.xdg")),
+            "HOME" => Some(OsString::from("/home/tolien")),
+            _ => None,
+        });
+        assert_eq!(
+            PathBuf::from("/home/tolien/.xdg/currentcost/config.toml"),
+            paths[0]
+        );
+        assert_eq!(PathBuf::from("/etc/currentcost/config.toml"), paths[1]);
+        assert_eq!(PathBuf::from("config.toml"), paths[3]);
+
+        let paths = search_path(|name| match name {
+            "HOME" => Some(OsString::from("/home/tolien")),
+            _ => None,
+        });
+        assert_eq!(
+            PathBuf::from("/home/tolien/.config/currentcost/config.toml"),
+            paths[0]
+        );
+    }
+
+    #[test]
+    fn environment_overrides_config_file() {
+        let mut table: Table = "[database]\ndb_name = \"currentcost\"\nignore_db = false"
+            .parse()
+            .unwrap();
+        let vars = [
+            ("CURRENTCOST_DATABASE_HOSTNAME", "/var/run/postgresql"),
+            ("CURRENTCOST_DATABASE_USER", "db_user"),
+            ("CURRENTCOST_DATABASE_BATCH_SIZE", "500"),
+            ("CURRENTCOST_DATABASE_IGNORE_DB", "true"),
+            ("CURRENTCOST_CONFIG", "/etc/currentcost/config.toml"),
+            ("HOME", "/home/tolien"),
+        ];
+        apply_environment(
+            &mut table,
+            vars.iter()
+                .map(|(name, value)| (String::from(*name), String::from(*value))),
+        );
+        let config = Config::from_table(&table).unwrap();
+
+        assert!(!config.database.use_database());
+        assert_eq!(Some("currentcost"), config.database.postgres.get_dbname());
+        assert_eq!(
+            &[Host::Unix(PathBuf::from("/var/run/postgresql"))],
+            config.database.postgres.get_hosts()
+        );
+        assert_eq!(Some("db_user"), config.database.postgres.get_user());
+        assert_eq!(500, config.database.batch_size());
+        assert_eq!(1, table.len());
+    }
+
+    #[test]
+    fn config_can_come_from_the_environment_alone() {
+        let mut table = Table::new();
+        let vars = [
+            ("CURRENTCOST_SERIAL_PORT", "/dev/ttyUSB0"),
+            ("CURRENTCOST_SERIAL_BIT_RATE", "9600"),
+            ("CURRENTCOST_LOGGING_DATA_LOG", "data.log"),
+            ("CURRENTCOST_LOGGING_CONNECT_DEBUG_LOG", "debug.log"),
+        ];
+        apply_environment(
+            &mut table,
+            vars.iter()
+                .map(|(name, value)| (String::from(*name), String::from(*value))),
+        );
+        let config = ConnectConfig::from_table(&table, &ConnectOverrides::default()).unwrap();
+
+        assert_eq!("/dev/ttyUSB0", config.port);
+        assert_eq!(9600, config.bit_rate);
+        assert!(config.data_log_path.ends_with("data.log"));
+    }
+
+    #[test]
+    fn database_can_be_given_a_connection_string() {
+        let password_file = env::temp_dir().join("currentcost-test-password");
+        fs::write(&password_file, "hunter2\n").unwrap();
+        let table = format!(
+            "[database]
+            connection_string = \"host=db.example.com port=5433 user=db_user dbname=currentcost sslmode=require\"
+            password_file = {password_file:?}"
+        )
+        .parse()
+        .unwrap();
+        let config = Config::from_table(&table).unwrap();
+        let postgres = config.database.postgres;
+        assert_eq!(
+            &[Host::Tcp(String::from("db.example.com"))],
+            postgres.get_hosts()
+        );
+        assert_eq!(&[5433], postgres.get_ports());
+        assert_eq!(Some(&b"hunter2"[..]), postgres.get_password());
+        assert_eq!(SslMode::Require, postgres.get_ssl_mode());
+        assert_eq!(
+            Some(&Duration::from_secs(10)),
+            postgres.get_connect_timeout()
+        );
+
+        let table = "[database]
+        connection_string = \"host=db.example.com\"
+        hostname = \"localhost\"
+        password = \"hunter2\"
+        password_file = \"/no/such/file\"
+        ssl_mode = \"always\""
+            .parse()
+            .unwrap();
+        let problems = Config::from_table(&table).err().unwrap();
+        assert_eq!(
+            vec![
+                "[database] hostname can't be used with connection_string",
+                "[database] password_file can't be used with password",
+                "[database] ssl_mode should be disable, prefer or require, not \"always\"",
+            ],
+            problems
+        );
+    }
+}
@@ -1,45 +1,173 @@
-extern crate roxmltree;
-use roxmltree::{Document, Node};
 extern crate signal_hook;
 
 #[macro_use]
 extern crate log;
 extern crate fern;
 
+use chrono::{DateTime, Utc};
+use clap::Parser;
 use fern::colors::{Color, ColoredLevelConfig};
 
-use std::fs;
+use std::collections::HashMap;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io;
+use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::Error;
 use std::io::Write;
-use std::path::Path;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
 use std::process;
 use std::str;
-use std::time::Duration;
-use toml::Table;
-
-mod reading;
-pub use crate::reading::CurrentCostReading;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use currentcost::capture::Capture;
+use currentcost::config::{self, ConnectConfig, ConnectOverrides};
+use currentcost::framing::Framer;
+use currentcost::impulse::ImpulseReading;
+use currentcost::protocol::{self, Message, ParseError};
+use currentcost::rfc2217::Rfc2217;
+use currentcost::sink::DatabaseSink;
+use currentcost::source::{ByteSource, Replay};
+
+/// Listens to a Currentcost device on a serial port and logs what it sends.
+#[derive(Parser)]
+#[command(name = "connect", version, about)]
+struct Cli {
+    /// Config file to use instead of the one that's looked for
+    #[arg(long, value_name = "PATH", long_help = config::CONFIG_HELP)]
+    config: Option<PathBuf>,
+
+    /// Serial port the device is on, e.g. /dev/ttyUSB0
+    #[arg(long)]
+    port: Option<String>,
+
+    /// Bit rate of the serial port
+    #[arg(long, value_name = "RATE")]
+    baud: Option<u32>,
+
+    /// File to write the data log to
+    #[arg(long, value_name = "PATH")]
+    data_log: Option<String>,
+
+    /// File to write connect's own log to
+    #[arg(long, value_name = "PATH")]
+    debug_log: Option<String>,
+
+    /// File to record exactly what the device sends to, which --replay can
+    /// read back
+    #[arg(long, value_name = "PATH")]
+    capture: Option<String>,
+
+    /// Lowest level of message to log, e.g. info or debug
+    #[arg(long, value_name = "LEVEL")]
+    log_level: Option<log::LevelFilter>,
+
+    /// Read the device's output from a TCP server, e.g. a serial-to-network
+    /// bridge, instead of a serial port or the [network] address
+    #[arg(long, value_name = "HOST:PORT", conflicts_with_all = ["replay", "port"])]
+    tcp: Option<String>,
+
+    /// Read raw device output captured earlier from a file, or - for stdin,
+    /// instead of a serial port
+    #[arg(long, value_name = "PATH")]
+    replay: Option<PathBuf>,
+
+    /// Replay messages as far apart as the <time> in each says they were sent
+    #[arg(long, requires = "replay")]
+    paced: bool,
+
+    /// List the serial ports that are available and exit
+    #[arg(long)]
+    list_ports: bool,
+
+    /// Check the config file, report any problems with it and exit
+    #[arg(long)]
+    check_config: bool,
+}
 
 fn main() {
-    let config = parse_config();
+    let cli = Cli::parse();
+    if cli.list_ports {
+        list_ports();
+        return;
+    }
+
+    // the logger isn't set up until the config has been read
+    let path = config::find(cli.config.as_deref());
+    let mut config = ConnectConfig::load(path.as_deref(), &cli.overrides()).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        process::exit(1);
+    });
+    if cli.check_config {
+        println!("Config from {} is valid", config::describe(path.as_deref()));
+        return;
+    }
     let logger_result = setup_logger(&config);
-    assert!(logger_result.is_ok(), "Error applying fern logger: {:?}", logger_result.err());
+    assert!(
+        logger_result.is_ok(),
+        "Error applying fern logger: {:?}",
+        logger_result.err()
+    );
 
     let signal_handler_result = setup_signal_handler();
     if signal_handler_result.is_err() {
         error!("Error applying signal handler, won't log SIGINT/SIGTERM");
     };
 
-    let port = get_serial_port(&config).unwrap_or_else(|err| {
-        error!("Error opening serial port: {err}");
+    let input = cli.input(&config);
+    let source = match input.open(&config) {
+        Ok(source) => source,
+        // the adapter may not have been plugged in yet
+        Err(err) if input.reconnects() => {
+            warn!("{err}");
+            reopen(&input, &config)
+        }
+        Err(err) => {
+            error!("{err}");
+            process::exit(1);
+        }
+    };
+
+    let database_sink = config
+        .database
+        .take()
+        .map(|database| DatabaseSink::new(database, &config.database_buffer_path));
+
+    let capture = config
+        .capture_log_path
+        .as_ref()
+        .map(|path| Capture::new(path, config.capture_max_size, config.capture_files));
+
+    listen(source, &input, &config, database_sink, capture);
+}
+
+fn list_ports() {
+    let ports = serialport::available_ports().unwrap_or_else(|err| {
+        eprintln!("Error listing serial ports: {err}");
         process::exit(1);
     });
 
-    listen_on_port(port, &config);
+    for port in ports {
+        match port.port_type {
+            serialport::SerialPortType::UsbPort(usb) => println!(
+                "{} (USB {:04x}:{:04x}{}{})",
+                port.port_name,
+                usb.vid,
+                usb.pid,
+                usb.serial_number
+                    .map(|serial_number| format!(", serial {serial_number}"))
+                    .unwrap_or_default(),
+                usb.product
+                    .map(|product| format!(", {product}"))
+                    .unwrap_or_default(),
+            ),
+            _ => println!("{}", port.port_name),
+        }
+    }
 }
 
 fn setup_logger(config: &ConnectConfig) -> std::result::Result<(), fern::InitError> {
@@ -68,7 +196,7 @@ fn setup_logger(config: &ConnectConfig) -> std::result::Result<(), fern::InitErr
             ));
         })
         // Add blanket level filter -
-        .level(log::LevelFilter::Debug)
+        .level(config.log_level.unwrap_or(log::LevelFilter::Debug))
         .level_for("tokio_reactor", log::LevelFilter::Off)
         .chain(std::io::stdout());
 
@@ -86,7 +214,7 @@ fn setup_logger(config: &ConnectConfig) -> std::result::Result<(), fern::InitErr
             ));
         })
         // Add blanket level filter -
-        .level(log::LevelFilter::Info)
+        .level(config.log_level.unwrap_or(log::LevelFilter::Info))
         .level_for("tokio_reactor", log::LevelFilter::Off)
         .chain(fern::log_file(&config.debug_log_path)?);
 
@@ -116,67 +244,342 @@ fn setup_signal_handler() -> std::result::Result<(), Error> {
     Ok(())
 }
 
-fn listen_on_port(mut port: Box<dyn serialport::SerialPort>, config: &ConnectConfig) {
-    info!("Port name: {}", port.name().unwrap());
+/// Where `connect` reads what the device sends from.
+enum Input {
+    Serial,
+    Network {
+        address: String,
+        rfc2217: bool,
+        idle_timeout: u32,
+    },
+    Replay {
+        path: PathBuf,
+        paced: bool,
+    },
+}
 
-    let mut serial_buf: Vec<u8> = vec![0; 1000];
-    let mut line: String = String::new();
-    info!(
-        "Receiving data on {} at {} baud",
-        port.name().unwrap(),
-        port.baud_rate().unwrap()
-    );
-    let mut file_buffer = get_file_buffer(config);
+impl Input {
+    fn open(&self, config: &ConnectConfig) -> Result<Box<dyn ByteSource>, String> {
+        match self {
+            Input::Serial => {
+                let port =
+                    find_port(config).and_then(|port_name| get_serial_port(&port_name, config))?;
+                info!(
+                    "Opened {} at {} baud",
+                    port.name(),
+                    port.baud_rate().unwrap_or_default()
+                );
+                Ok(Box::new(port))
+            }
+            Input::Network {
+                address,
+                rfc2217,
+                idle_timeout,
+            } => {
+                let stream = connect_to(address, Duration::new(config.timeout.into(), 0))
+                    .map_err(|err| format!("Problem connecting to {address}: {err}"))?;
+                // so reads return when it goes quiet, and it can be made again
+                stream
+                    .set_read_timeout(Some(Duration::new((*idle_timeout).into(), 0)))
+                    .map_err(|err| format!("Problem setting a timeout on {address}: {err}"))?;
+                info!("Connected to {address}");
+                if *rfc2217 {
+                    let port = Rfc2217::new(stream, config.bit_rate).map_err(|err| {
+                        format!("Problem setting up the port at {address}: {err}")
+                    })?;
+                    Ok(Box::new(port))
+                } else {
+                    Ok(Box::new(stream))
+                }
+            }
+            Input::Replay { path, paced } if path.as_os_str() == "-" => {
+                Ok(Box::new(Replay::new(io::stdin().lock(), "stdin", *paced)))
+            }
+            Input::Replay { path, paced } => {
+                let file = File::open(path)
+                    .map_err(|err| format!("Problem opening {}: {err}", path.display()))?;
+                let name = path.display().to_string();
+                Ok(Box::new(Replay::new(BufReader::new(file), &name, *paced)))
+            }
+        }
+    }
+
+    /// How long it can go without sending anything before it's taken to have
+    /// gone, for a bridge that's still connected but no longer passing
+    /// anything on.
+    fn idle_timeout(&self) -> Option<Duration> {
+        match self {
+            Input::Network { idle_timeout, .. } => Some(Duration::new((*idle_timeout).into(), 0)),
+            _ => None,
+        }
+    }
+
+    /// Whether to open it again when it stops, rather than stopping too, as a
+    /// replay does once it reaches the end.
+    fn reconnects(&self) -> bool {
+        !matches!(self, Input::Replay { .. })
+    }
+}
+
+/// Connects to the first of the addresses `address` resolves to that
+/// answers within `timeout`.
+fn connect_to(address: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "it didn't resolve to an address");
+    for socket_address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket_address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+fn listen(
+    mut source: Box<dyn ByteSource>,
+    input: &Input,
+    config: &ConnectConfig,
+    database_sink: Option<DatabaseSink>,
+    mut capture: Option<Capture>,
+) {
+    info!("Receiving data from {}", source.name());
+    let mut file_buffer = get_file_buffer(&config.data_log_path);
+    let mut history_buffer = config.history_log_path.as_deref().map(get_file_buffer);
+    let mut rejections = RejectionCounts::default();
+    let mut last_impulses = LastImpulses::default();
     loop {
-        match port.read(serial_buf.as_mut_slice()) {
-            Ok(t) => {
-                let s = received_bytes_to_string(&serial_buf[..t]);
-                line.push_str(s);
-                if s.contains('\n') {
-                    if let Ok(reading) = parse_line_from_device(&line) {
+        let err = read_lines(
+            source.as_mut(),
+            input.idle_timeout(),
+            capture.as_mut(),
+            |message, received| {
+                let line = String::from_utf8_lossy(message);
+                let mut parsed = protocol::parse_message_as(&line, config.dialect);
+                // replayed messages are dated when they were first received
+                if let (Ok(message), Some(received)) = (&mut parsed, received) {
+                    message.set_received(received);
+                }
+                match parsed {
+                    Ok(Message::Reading(reading)) => {
+                        debug!("{reading:?}");
+                        let log_line = reading.to_log();
+                        write_to_log(&log_line, &mut file_buffer);
+                        if let Some(database_sink) = database_sink.as_ref() {
+                            database_sink.write(&log_line);
+                        }
+                    }
+                    Ok(Message::Impulse(mut reading)) => {
+                        last_impulses.record(&mut reading);
                         debug!("{reading:?}");
                         write_to_log(&reading.to_log(), &mut file_buffer);
                     }
-                    line = String::new();
+                    Ok(Message::History(history)) => {
+                        debug!("Received {} history records", history.len());
+                        if let Some(history_buffer) = history_buffer.as_mut() {
+                            for record in history {
+                                write_to_log(&record.to_log(), history_buffer);
+                            }
+                        }
+                    }
+                    Ok(Message::Unknown) => debug!("Ignoring message: {:?}", line.trim()),
+                    Err(err) => {
+                        let count = rejections.record(&err);
+                        log!(
+                            config.rejected_line_level,
+                            "Rejected line ({} {} so far): {err}: {:?}",
+                            count,
+                            err.kind(),
+                            line.trim()
+                        );
+                    }
+                }
+            },
+        );
+
+        if !input.reconnects() {
+            match err.kind() {
+                io::ErrorKind::UnexpectedEof => info!("Reached the end of {}", source.name()),
+                _ => error!("Problem reading {}: {err}", source.name()),
+            }
+            return;
+        }
+        error!("Lost {}: {err}", source.name());
+        // close it first, so it can be opened again at the same path
+        drop(source);
+        source = reopen(input, config);
+        info!("Receiving data from {} again", source.name());
+    }
+}
+
+/// Reads from `source`, passing on each message it sends along with when it
+/// was received if it's being replayed, until reading fails with something
+/// other than a timeout, which usually means the device has been unplugged,
+/// or there's nothing more to read, or nothing has arrived for `idle_timeout`.
+/// Everything read is also written to `capture`, if there is one, as it was
+/// read.
+fn read_lines(
+    source: &mut dyn ByteSource,
+    idle_timeout: Option<Duration>,
+    mut capture: Option<&mut Capture>,
+    mut on_message: impl FnMut(&[u8], Option<DateTime<Utc>>),
+) -> io::Error {
+    let mut serial_buf: Vec<u8> = vec![0; 1000];
+    let mut framer = Framer::default();
+    let mut last_received = Instant::now();
+    loop {
+        match source.read(serial_buf.as_mut_slice()) {
+            Ok(0) => return io::Error::new(io::ErrorKind::UnexpectedEof, "it was closed"),
+            Ok(t) => {
+                last_received = Instant::now();
+                // captured as read, before it's split into messages
+                if let Some(capture) = capture.as_mut() {
+                    let received = source.received_at().unwrap_or_else(Utc::now);
+                    capture.write(received, &serial_buf[..t]);
+                }
+                for message in framer.push(&serial_buf[..t]) {
+                    on_message(&message, source.received_at());
+                }
+            }
+            // sockets' read timeouts are WouldBlock on Unix but TimedOut on Windows
+            Err(ref e)
+                if idle_timeout.is_some()
+                    && matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+            {
+                let idle_timeout = idle_timeout.expect("checked above");
+                if last_received.elapsed() >= idle_timeout {
+                    let message =
+                        format!("nothing received from it for {}s", idle_timeout.as_secs());
+                    return io::Error::new(io::ErrorKind::TimedOut, message);
                 }
             }
-            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => (),
-            Err(e) => error!("{e}"),
+            Err(ref e)
+                if e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return e,
         }
     }
 }
 
-fn get_file_buffer(config: &ConnectConfig) -> BufWriter<File> {
+/// Opens `input` again, waiting longer after each failed attempt, and looking
+/// for the USB device again in case it's come back at another path.
+fn reopen(input: &Input, config: &ConnectConfig) -> Box<dyn ByteSource> {
+    let mut backoff = Backoff::default();
+    loop {
+        let delay = backoff.next_delay();
+        info!("Reopening in {}s", delay.as_secs());
+        thread::sleep(delay);
+        match input.open(config) {
+            Ok(source) => return source,
+            Err(err) => warn!("{err}"),
+        }
+    }
+}
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// The time to wait before each attempt to reopen the port, doubling each
+/// time up to a minute.
+struct Backoff {
+    delay: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            delay: MIN_RECONNECT_DELAY,
+        }
+    }
+}
+
+impl Backoff {
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(MAX_RECONNECT_DELAY);
+        delay
+    }
+}
+
+/// Running totals of rejected lines, keyed by `ParseError::kind`.
+#[derive(Default)]
+struct RejectionCounts {
+    counts: HashMap<&'static str, u64>,
+}
+
+impl RejectionCounts {
+    fn record(&mut self, err: &ParseError) -> u64 {
+        let count = self.counts.entry(err.kind()).or_insert(0);
+        *count += 1;
+        *count
+    }
+}
+
+/// The last reading from each impulse sensor, keyed by device and sensor as
+/// two monitors can both have a sensor with the same number.
+#[derive(Default)]
+struct LastImpulses {
+    readings: HashMap<(String, i32), ImpulseReading>,
+}
+
+impl LastImpulses {
+    /// Works out `reading`'s rate since the last one from the same sensor,
+    /// then keeps it for the next.
+    fn record(&mut self, reading: &mut ImpulseReading) {
+        let key = (reading.device.clone(), reading.sensor);
+        if let Some(previous) = self.readings.get(&key) {
+            reading.rate = reading.rate_since(previous);
+        }
+        self.readings.insert(key, reading.clone());
+    }
+}
+
+fn get_file_buffer(path: &str) -> BufWriter<File> {
     BufWriter::new(
         OpenOptions::new()
             .append(true)
             .create(true)
-            .open(&config.data_log_path)
+            .open(path)
             .unwrap(),
     )
 }
 
-fn received_bytes_to_string(bytes: &[u8]) -> &str {
-    str::from_utf8(bytes).unwrap_or_else(|err| {
-        error!(
-            "Error: {} in string {:?}",
-            err,
-            String::from_utf8_lossy(bytes)
-        );
-        ""
-    })
+/// The path of the configured port, or of the USB device it's configured to
+/// look for, wherever that is now.
+fn find_port(config: &ConnectConfig) -> Result<String, String> {
+    let Some(usb_device) = &config.usb_device else {
+        return Ok(config.port.clone());
+    };
+
+    let ports = serialport::available_ports()
+        .map_err(|err| format!("Problem listing serial ports: {err}"))?;
+    ports
+        .into_iter()
+        .find(|port| match &port.port_type {
+            serialport::SerialPortType::UsbPort(usb) => usb_device.matches(usb),
+            _ => false,
+        })
+        .map(|port| port.port_name)
+        .ok_or_else(|| {
+            format!(
+                "No USB serial port {:04x}:{:04x} found",
+                usb_device.vid, usb_device.pid
+            )
+        })
 }
 
-fn get_serial_port(config: &ConnectConfig) -> Result<Box<dyn serialport::SerialPort>, String> {
-    let builder = serialport::new(&config.port, config.bit_rate)
+fn get_serial_port(
+    port_name: &str,
+    config: &ConnectConfig,
+) -> Result<Box<dyn serialport::SerialPort>, String> {
+    let builder = serialport::new(port_name, config.bit_rate)
         .timeout(Duration::new(config.timeout.into(), 0))
         .baud_rate(config.bit_rate);
 
     match builder.open() {
         Ok(port) => Ok(port),
-        Err(error_description) => Err(format!(
-            "Problem opening serial port: {error_description}"
-        )),
+        Err(error_description) => Err(format!("Problem opening serial port: {error_description}")),
     }
 }
 
@@ -187,170 +590,263 @@ fn write_to_log(line: &str, writer: &mut BufWriter<File>) {
     assert!(flush_result.is_ok(), "Failed to flush writes");
 }
 
-#[derive(Debug)]
-struct ConnectConfig {
-    port: String,
-    bit_rate: u32,
-    timeout: u32,
-    data_log_path: String,
-    debug_log_path: String,
-}
-
-impl ConnectConfig {
-    pub fn new(args: &toml::Table) -> Self {
-        #![allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
-        let serial_args = &args["serial"];
-        let port = String::from(serial_args["port"].as_str().unwrap());
-        let bit_rate_int = serial_args["bit_rate"].as_integer().unwrap();
-        assert!(bit_rate_int > 0 && bit_rate_int < (i64::from(u32::MAX)));
-        let bit_rate = bit_rate_int as u32;
-        let timeout = serial_args["timeout"].as_integer().unwrap() as u32;
-
-        let logging_args = &args["logging"];
-        let data_log_dir = logging_args["data_log_output_dir"].as_str().unwrap();
-        let data_log = logging_args["data_log"].as_str().unwrap();
-        let data_log_path = String::from(
-            Path::join(Path::new(data_log_dir), data_log)
-                .to_str()
-                .unwrap(),
-        );
-
-        let debug_log_dir = args["logging"]["connect_debug_log_location"]
-            .as_str()
-            .unwrap();
-        let debug_log = args["logging"]["connect_debug_log"].as_str().unwrap();
-        let debug_log_path = String::from(
-            Path::join(Path::new(debug_log_dir), debug_log)
-                .to_str()
-                .unwrap(),
-        );
-
-        Self {
-            port,
-            bit_rate,
-            timeout,
-            data_log_path,
-            debug_log_path,
+impl Cli {
+    /// What to read from: a replay if one was asked for, then the network
+    /// address if there is one, given with `--tcp` or in the config file.
+    fn input(&self, config: &ConnectConfig) -> Input {
+        match (&self.replay, &config.network) {
+            (Some(path), _) => Input::Replay {
+                path: path.clone(),
+                paced: self.paced,
+            },
+            (None, Some(network)) => Input::Network {
+                address: network.address.clone(),
+                rfc2217: network.rfc2217,
+                idle_timeout: network.idle_timeout,
+            },
+            (None, None) => Input::Serial,
         }
     }
-}
-
-fn parse_config() -> ConnectConfig {
-    let properties = fs::read_to_string("config.toml").unwrap();
-    let values = &properties.parse::<Table>().unwrap();
-
-    ConnectConfig::new(values)
-}
 
-fn get_element_from_xmldoc(root: &Document, element_name: &str, expected_count: usize) -> String {
-    let nodes: Vec<Node> = root
-        .descendants()
-        .filter(|n| n.has_tag_name(element_name))
-        .collect();
-    if nodes.len() != expected_count {
-        return String::new();
+    fn overrides(&self) -> ConnectOverrides {
+        ConnectOverrides {
+            without_serial: self.replay.is_some(),
+            port: self.port.clone(),
+            network_address: self.tcp.clone(),
+            bit_rate: self.baud,
+            data_log_path: self.data_log.clone(),
+            debug_log_path: self.debug_log.clone(),
+            capture_log_path: self.capture.clone(),
+            log_level: self.log_level,
+        }
     }
-    assert_eq!(nodes.len(), expected_count);
-    let value = nodes[0].text().unwrap();
-
-    String::from(value)
 }
 
-fn parse_line_from_device(line: &str) -> std::result::Result<CurrentCostReading, &'static str> {
-    if let Ok(parse_state) = Document::parse(line) {
-        let doc = parse_state;
-
-        let source = get_element_from_xmldoc(&doc, "src", 1);
-        if source.is_empty() {
-            return Err("No device found in data");
-        }
-
-        let pwr = get_element_from_xmldoc(&doc, "watts", 1);
-        let power;
-        if pwr.is_empty() {
-            return Err("No power value found in data");
-        } else if let Ok(parsed_power) = pwr.parse::<i32>() {
-            power = parsed_power;
-        } else {
-            return Err("Invalid power value - couldn't parse an an integer");
-        }
-
-        let temp = get_element_from_xmldoc(&doc, "tmpr", 1);
-        let temperature;
-        if temp.is_empty() {
-            return Err("No temperature value found in data");
-        } else if let Ok(parsed_temp) = temp.parse::<f32>() {
-            temperature = parsed_temp;
-        } else {
-            return Err("Invalid temperature value - couldn't parse a float");
-        }
+#[cfg(test)]
+mod tests {
+    use super::{get_serial_port, read_lines, Backoff, Cli, Input, LastImpulses, RejectionCounts};
+    use chrono::prelude::*;
+    use clap::Parser;
+    use currentcost::capture::{parse_capture_line, Capture};
+    use currentcost::config::{ConnectConfig, ConnectOverrides};
+    use currentcost::impulse::ImpulseReading;
+    use currentcost::protocol::ParseError;
+    use serialport::{SerialPort, TTYPort};
+    use std::env;
+    use std::fs;
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
 
-        let sens = get_element_from_xmldoc(&doc, "sensor", 1);
-        if sens.is_empty() {
-            return Err("No sensor value found in data");
-        }
+    #[test]
+    fn rejections_get_counted_by_kind() {
+        let mut rejections = RejectionCounts::default();
+
+        assert_eq!(1, rejections.record(&ParseError::MissingField("watts")));
+        assert_eq!(2, rejections.record(&ParseError::MissingField("tmpr")));
+        assert_eq!(
+            1,
+            rejections.record(&ParseError::MalformedXml(String::from("unexpected end")))
+        );
+    }
 
-        let Ok(sensor) = sens.parse::<i32>() else {
-            return Err("Invalid sensor ID - couldn't parse as an integer");
+    #[test]
+    fn impulse_rates_are_worked_out_per_device() {
+        let reading = |device: &str, minute, impulses| ImpulseReading {
+            timestamp: Utc.with_ymd_and_hms(2019, 8, 20, 15, minute, 0).unwrap(),
+            device: String::from(device),
+            sensor: 9,
+            sensor_type: 2,
+            radio_id: None,
+            impulses,
+            impulses_per_unit: 1000,
+            rate: None,
         };
+        let mut last_impulses = LastImpulses::default();
+
+        let mut first = reading("CC128-v1.29", 0, 89_000);
+        last_impulses.record(&mut first);
+        // another monitor's sensor 9 isn't compared with the first's
+        let mut other = reading("CC128-v0.11", 6, 12);
+        last_impulses.record(&mut other);
+        let mut second = reading("CC128-v1.29", 6, 89_100);
+        last_impulses.record(&mut second);
+
+        assert_eq!(None, first.rate);
+        assert_eq!(None, other.rate);
+        assert_eq!(Some(1.0), second.rate);
+    }
 
-        let reading = CurrentCostReading {
-            timestamp: chrono::Utc::now(),
-            device: source,
-            sensor,
-            temperature,
-            power,
-        };
+    #[test]
+    fn command_line_values_override_the_config_file() {
+        let cli = Cli::parse_from([
+            "connect",
+            "--port",
+            "/dev/ttyUSB0",
+            "--baud",
+            "2400",
+            "--log-level",
+            "warn",
+        ]);
+        let overrides = cli.overrides();
+
+        assert_eq!(Some(String::from("/dev/ttyUSB0")), overrides.port);
+        assert_eq!(Some(2400), overrides.bit_rate);
+        assert_eq!(None, overrides.data_log_path);
+        assert_eq!(Some(log::LevelFilter::Warn), overrides.log_level);
+        assert!(!overrides.without_serial);
+
+        let cli = Cli::parse_from(["connect", "--replay", "capture.log", "--paced"]);
+        assert!(matches!(
+            cli.input(&config_for(&cli)),
+            Input::Replay { paced: true, .. }
+        ));
+        assert!(cli.overrides().without_serial);
+        assert!(Cli::try_parse_from(["connect", "--paced"]).is_err());
+
+        let cli = Cli::parse_from(["connect", "--tcp", "ser2net.local:3001"]);
+        assert!(matches!(
+            cli.input(&config_for(&cli)),
+            Input::Network { rfc2217: false, .. }
+        ));
+        let both = ["connect", "--tcp", "ser2net.local:3001", "--port", "COM3"];
+        assert!(Cli::try_parse_from(both).is_err());
+    }
 
-        Ok(reading)
-    } else {
-        Err("Error parsing XML")
+    fn config_for(cli: &Cli) -> ConnectConfig {
+        let overrides = ConnectOverrides {
+            data_log_path: Some(String::from("data.log")),
+            debug_log_path: Some(String::from("debug.log")),
+            ..cli.overrides()
+        };
+        ConnectConfig::from_table(&toml::Table::new(), &overrides).unwrap()
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::parse_line_from_device;
+    #[test]
+    fn reconnection_backs_off_up_to_a_minute() {
+        let mut backoff = Backoff::default();
+        let delays: Vec<u64> = (0..8).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(vec![1, 2, 4, 8, 16, 32, 60, 60], delays);
+    }
 
     #[test]
-    fn line_gets_parsed() {
-        let sample_text = " <msg><src>CC128-v1.29</src><dsb>02353</dsb><time>10:27:59</time><tmpr>21.4</tmpr><sensor>0</sensor><id>04066</id><type>1</type><ch1><watts>00479</watts></ch1></msg>";
-        let parsed = parse_line_from_device(sample_text).unwrap();
-
-        //assert_eq!(1555188288, parsed.timestamp);
-        assert_eq!("CC128-v1.29", parsed.device);
-        assert_eq!(0, parsed.sensor);
-        assert_eq!(479, parsed.power);
-        assert_eq!(21.4, parsed.temperature);
+    fn lines_are_read_until_the_port_goes_away() {
+        let (mut device, mut port) = TTYPort::pair().unwrap();
+        port.set_timeout(Duration::from_millis(100)).unwrap();
+        let mut port: Box<dyn SerialPort> = Box::new(port);
+        let device = thread::spawn(move || {
+            device
+                .write_all(b"<msg><src>CC128-v1.29</src></msg>\r\n")
+                .unwrap();
+            thread::sleep(Duration::from_millis(50));
+            device.write_all(b"<msg><src>CC128-v1.29</src>").unwrap();
+            thread::sleep(Duration::from_millis(50));
+            device.write_all(b"</msg>\r\n").unwrap();
+            // unplugging it loses anything that hasn't been read yet
+            thread::sleep(Duration::from_millis(200));
+        });
+
+        let capture_path = env::temp_dir().join("currentcost-read-lines-capture.log");
+        let _ = fs::remove_file(&capture_path);
+        let mut capture = Capture::new(&capture_path, 1_000_000, 1);
+        let mut lines = Vec::new();
+        let err = read_lines(&mut port, None, Some(&mut capture), |message, _| {
+            lines.push(message.to_vec());
+        });
+        assert_ne!(ErrorKind::TimedOut, err.kind());
+        assert_eq!(2, lines.len());
+        assert_eq!(b"<msg><src>CC128-v1.29</src></msg>", lines[1].as_slice());
+        device.join().unwrap();
+
+        // the capture has every byte sent, line endings and all
+        let captured: Vec<u8> = fs::read(&capture_path)
+            .unwrap()
+            .split_inclusive(|&byte| byte == b'\n')
+            .flat_map(|line| parse_capture_line(line).unwrap().1)
+            .collect();
+        assert_eq!(
+            b"<msg><src>CC128-v1.29</src></msg>\r\n<msg><src>CC128-v1.29</src></msg>\r\n".to_vec(),
+            captured
+        );
+        fs::remove_file(&capture_path).unwrap();
     }
 
     #[test]
-    fn invalid_lines_return_errors() {
-        let mut sample_text = "<msg><src>CC128-v1.29</src><dsb>02353</dsb><time>10:27:59</time><tmpr>21.4</tmpr><sensor>0</sensor><id>04066</id><type>1</type><ch1><watts>p</watts></ch1></msg>";
-        let parse_result = parse_line_from_device(sample_text);
+    fn lines_are_read_from_an_rfc2217_bridge() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let bridge = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut setup = [0; 43];
+            stream.read_exact(&mut setup).unwrap();
+            // the bit rate, 57600, is set after the options are negotiated
+            assert_eq!([255, 250, 44, 1, 0, 0, 0xe1, 0, 255, 240], setup[12..22]);
+            stream
+                .write_all(b"\xff\xfd\x2c<msg><src>CC128-v1.29</src>")
+                .unwrap();
+            thread::sleep(Duration::from_millis(50));
+            stream
+                .write_all(b"\xff\xfa\x2c\x65\x00\x00\xe1\x00\xff\xf0</msg>\r\n")
+                .unwrap();
+        });
+
+        let cli = Cli::parse_from(["connect", "--tcp", &address]);
+        let mut config = config_for(&cli);
+        config.network.as_mut().unwrap().rfc2217 = true;
+        let mut source = cli.input(&config).open(&config).unwrap();
+        bridge.join().unwrap();
+
+        let mut lines = Vec::new();
+        let err = read_lines(source.as_mut(), None, None, |message, _| {
+            lines.push(message.to_vec())
+        });
+        assert_eq!(ErrorKind::UnexpectedEof, err.kind());
+        assert_eq!(vec![b"<msg><src>CC128-v1.29</src></msg>".to_vec()], lines);
+    }
 
-        assert!(parse_result.is_err());
+    /// A bridge that's gone quiet, read as it would be on Windows, where a
+    /// socket's read timeout fails with `TimedOut` rather than `WouldBlock`.
+    struct QuietBridge;
 
-        sample_text = "<msg><src>CC128-v1.29</src><dsb>02353</dsb><time>10:27:59</time><tmpr>2a.4</tmpr><sensor>0</sensor><id>04066</id><type>1</type><ch1><watts>00479</watts></ch1></msg>";
-        let parse_result = parse_line_from_device(sample_text);
-        assert!(parse_result.is_err());
+    impl Read for QuietBridge {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            thread::sleep(Duration::from_millis(10));
+            Err(ErrorKind::TimedOut.into())
+        }
+    }
 
-        sample_text = "<msg><src>CC128-v1.29</src><dsb>02353</dsb><time>10:27:59</time><tmpr>20.4</tmpr><sensor>p</sensor><id>04066</id><type>1</type><ch1><watts>00479</watts></ch1></msg>";
-        let parse_result = parse_line_from_device(sample_text);
-        assert!(parse_result.is_err());
+    impl currentcost::source::ByteSource for QuietBridge {
+        fn name(&self) -> String {
+            String::from("quiet bridge")
+        }
     }
 
     #[test]
-    fn history_line_gets_ignored() {
-        let sample_text = "<msg><src>CC128-v1.29</src><dsb>02371</dsb><time>09:23:30</time><hist><dsw>02373</dsw><type>1</type><units>kwhr</units><data><sensor>0</sensor><m003>597.250</m003><m002>681.250</m002><m001>613.250</m001></data><data><sensor>1</sensor><m003>4.750</m003><m002>2.250</m002><m001>2.000</m001></data><data><sensor>2</sensor><m003>0.000</m003><m002>0.000</m002><m001>0.000</m001></data><data><sensor>3</sensor><m003>0.000</m003><m002>0.000</m002><m001>0.000</m001></data><data><sensor>4</sensor><m003>0.000</m003><m002>0.000</m002><m001>0.000</m001></data><data><sensor>5</sensor><m003>0.000</m003><m002>0.000</m002><m001>0.000</m001></data><data><sensor>6</sensor><m003>0.000</m003><m002>0.000</m002><m001>0.000</m001></data><data><sensor>7</sensor><m003>0.000</m003><m002>0.000</m002><m001>0.000</m001></data><data><sensor>8</sensor><m003>0.000</m003><m002>0.000</m002><m001>0.000</m001></data><data><sensor>9</sensor><m003>0.000</m003><m002>0.000</m002><m001>0.000</m001></data></hist></msg>";
-        let parse_result = parse_line_from_device(sample_text);
-        assert!(parse_result.is_err());
+    fn quiet_bridges_time_out_however_the_timeout_is_reported() {
+        let err = read_lines(
+            &mut QuietBridge,
+            Some(Duration::from_millis(50)),
+            None,
+            |_, _| panic!("nothing was sent"),
+        );
+        assert_eq!(ErrorKind::TimedOut, err.kind());
     }
 
     #[test]
-    fn history_line_gets_ignored_again() {
-        let sample_text = "<msg><src>CC128-v1.29</src><dsb>02371</dsb><time>23:01:20</time><hist><dsw>02373</dsw><type>1</type><units>kwhr</units><data><sensor>0</sensor><h730>1.799</h730><h728>1.553</h728><h726>2.986</h726><h724>1.125</h724></data><data><sensor>1</sensor><h730>0.000</h730><h728>0.000</h728><h726>0.023</h726><h724>0.000</h724></data><data><sensor>2</sensor><h730>0.000</h730><h728>0.000</h728><h726>0.000</h726><h724>0.000</h724></data><data><sensor>3</sensor><h730>0.000</h730><h728>0.000</h728><h726>0.000</h726><h724>0.000</h724></data><data><sensor>4</sensor><h730>0.000</h730><h728>0.000</h728><h726>0.000</h726><h724>0.000</h724></data><data><sensor>5</sensor><h730>0.000</h730><h728>0.000</h728><h726>0.000</h726><h724>0.000</h724></data><data><sensor>6</sensor><h730>0.000</h730><h728>0.000</h728><h726>0.000</h726><h724>0.000</h724></data><data><sensor>7</sensor><h730>0.000</h730><h728>0.000</h728><h726>0.000</h726><h724>0.000</h724></data><data><sensor>8</sensor><h730>0.000</h730><h728>0.000</h728><h726>0.000</h726><h724>0.000</h724></data><data><sensor>9</sensor><h730>0.000</h730><h728>0.000</h728><h726>0.000</h726><h724>0.000</h724></data></hist></msg>\n<msg>";
-        let parse_result = parse_line_from_device(sample_text);
-        assert!(parse_result.is_err());
+    fn port_can_be_reopened_at_its_path() {
+        let (_device, port) = TTYPort::pair().unwrap();
+        let overrides = ConnectOverrides {
+            port: port.name(),
+            data_log_path: Some(String::from("data.log")),
+            debug_log_path: Some(String::from("debug.log")),
+            ..ConnectOverrides::default()
+        };
+        let config = ConnectConfig::from_table(&toml::Table::new(), &overrides).unwrap();
+        let port_name = port.name().unwrap();
+        drop(port);
+
+        let reopened = get_serial_port(&port_name, &config).unwrap();
+        assert_eq!(Some(port_name), reopened.name());
     }
 }
@@ -0,0 +1,350 @@
+use std::io::{self, BufRead};
+
+use crate::reading::LOG_FORMAT_VERSION;
+use crate::{CurrentcostLine, HistoryLine, ImpulseLine, LogLine};
+
+/// The lines of a data log, sorted by kind.
+#[derive(Debug, Default)]
+pub struct ParsedLog {
+    pub readings: Vec<CurrentcostLine>,
+    pub impulses: Vec<ImpulseLine>,
+    pub history: Vec<HistoryLine>,
+}
+
+impl ParsedLog {
+    /// Parses `line` and adds it to the lines of its kind, logging and
+    /// skipping it if it can't be parsed.
+    pub fn push_line(&mut self, line: &str) {
+        match parse_log_line(line) {
+            Ok(LogLine::Reading(reading)) => self.readings.push(reading),
+            Ok(LogLine::Impulse(impulse)) => self.impulses.push(impulse),
+            Ok(LogLine::History(history)) => self.history.push(history),
+            Err(err) => log::error!("Skipping invalid line ({err}): {line}"),
+        }
+    }
+}
+
+#[must_use]
+pub fn parse_log(lines: Vec<&str>) -> ParsedLog {
+    let mut parsed = ParsedLog::default();
+    for line in lines {
+        parsed.push_line(line);
+    }
+    parsed
+}
+
+/// Reads a data log `batch_size` lines at a time, so that a log covering
+/// years doesn't have to fit in memory to be imported.
+pub struct LogBatches<R> {
+    lines: io::Lines<R>,
+    batch_size: usize,
+}
+
+impl<R: BufRead> LogBatches<R> {
+    pub fn new(reader: R, batch_size: usize) -> Self {
+        assert!(batch_size > 0);
+        Self {
+            lines: reader.lines(),
+            batch_size,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for LogBatches<R> {
+    type Item = io::Result<ParsedLog>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut batch = ParsedLog::default();
+        let mut line_count = 0;
+        while line_count < self.batch_size {
+            match self.lines.next() {
+                Some(Ok(line)) => batch.push_line(&line),
+                Some(Err(err)) => return Some(Err(err)),
+                None => break,
+            }
+            line_count += 1;
+        }
+
+        if line_count == 0 {
+            None
+        } else {
+            Some(Ok(batch))
+        }
+    }
+}
+
+/// Parses a line of the data log in either the current JSON format or the
+/// original comma-separated one, which only ever held readings.
+pub fn parse_log_line(line: &str) -> Result<LogLine, &'static str> {
+    let line = line.trim();
+    if line.starts_with('{') {
+        parse_json_line(line)
+    } else {
+        parse_line(line).map(LogLine::Reading)
+    }
+}
+
+fn parse_json_line(line: &str) -> Result<LogLine, &'static str> {
+    let Ok(value) = serde_json::from_str::<serde_json::Value>(line) else {
+        return Err("Invalid JSON");
+    };
+    match value.get("v").and_then(serde_json::Value::as_u64) {
+        Some(version) if version == u64::from(LOG_FORMAT_VERSION) => {
+            serde_json::from_value(value).map_err(|_| "Invalid log record")
+        }
+        Some(_) => Err("Unsupported log format version"),
+        None => Err("No log format version"),
+    }
+}
+
+fn parse_line(line: &str) -> Result<CurrentcostLine, &'static str> {
+    let mut position = 0;
+    let mut timestamp = 0;
+    let mut power = 0;
+    let mut sensor = 0;
+    let mut temperature = 0.0;
+
+    for item in line.split(',') {
+        if position == 1 {
+            let timestamp_string = item.trim();
+            if let Ok(time) = timestamp_string.parse::<i32>() {
+                timestamp = time;
+            } else {
+                return Err("Invalid timestamp");
+            };
+        } else if position == 2 {
+            if let Some(Ok(sns)) = item
+                .trim()
+                .strip_prefix("Sensor ")
+                .map(|sensor_string| sensor_string.trim().parse::<i32>())
+            {
+                sensor = sns;
+            } else {
+                return Err("Invalid sensor");
+            };
+        } else if position == 3 {
+            if let Some(Ok(tmpr)) = item
+                .trim()
+                .strip_suffix("\u{b0}C")
+                .map(|temperature_string| temperature_string.parse::<f32>())
+            {
+                temperature = tmpr;
+            } else {
+                return Err("Invalid temperature");
+            };
+        } else if position == 4 {
+            if let Some(Ok(pwr)) = item
+                .trim()
+                .strip_suffix('W')
+                .map(|power_string| power_string.trim().parse::<i32>())
+            {
+                power = pwr;
+            } else {
+                return Err("Invalid power");
+            };
+        }
+        position += 1;
+    }
+
+    if position == 5 {
+        Ok(CurrentcostLine {
+            timestamp,
+            device: String::new(),
+            sensor,
+            temperature,
+            power,
+            channels: vec![power],
+            radio_id: None,
+            sensor_type: None,
+            days_since_birth: None,
+            device_time: None,
+        })
+    } else {
+        Err("Failed to parse line - not enough pieces")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_line, parse_log, parse_log_line, LogBatches};
+    use crate::history::{HistoryKind, HistoryRecord};
+    use crate::impulse::ImpulseReading;
+    use crate::{CurrentCostReading, HistoryLine, ImpulseLine, LogLine};
+    use chrono::prelude::*;
+
+    #[test]
+    fn line_gets_parsed() {
+        let sample_text = "13/04/2019 20:44:48, 1555188288, Sensor 0, 21.200000°C, 631W";
+        let parsed_result = parse_line(sample_text);
+        let parsed = parsed_result.unwrap();
+
+        assert_eq!(1555188288, parsed.timestamp);
+        assert_eq!(0, parsed.sensor);
+        assert_eq!(21.2, parsed.temperature);
+        assert_eq!(631, parsed.power);
+        assert_eq!(vec![631], parsed.channels);
+    }
+
+    #[test]
+    fn multilines_get_parsed() {
+        let sample_text = "14/04/2019 23:25:26, 1555284326, Sensor 1, 22.100000°C, 0W
+        14/04/2019 23:25:29, 1555284329, Sensor 0, 22.100000°C, 544W
+        14/04/2019 23:25:32, 1555284332, Sensor 1, 22.100000°C, 0W";
+        let parsed = parse_log(sample_text.lines().collect()).readings;
+
+        assert_eq!(3, parsed.len());
+        assert_eq!(1555284326, parsed[0].timestamp);
+        assert_eq!(1555284329, parsed[1].timestamp);
+        assert_eq!(1555284332, parsed[2].timestamp);
+    }
+
+    #[test]
+    fn empty_string_get_parsed() {
+        let sample_text = "";
+        let parsed = parse_log(sample_text.lines().collect()).readings;
+
+        assert_eq!(0, parsed.len());
+    }
+
+    #[test]
+    fn invalid_line_gets_dropped() {
+        let sample_text = "14/04/2019 23:25:26, Sensor 1, 22.100000°C, 0W
+        14/04/2019 23:25:29, 1555284329, Sensor 0, 22.100000°C, 544W
+        14/04/2019 23:25:32, 1555284332, Sensor, 22.100000°C, 0W";
+
+        let parsed = parse_log(sample_text.lines().collect()).readings;
+
+        assert_eq!(1, parsed.len());
+    }
+
+    #[test]
+    fn short_legacy_lines_are_rejected() {
+        assert!(parse_line("13/04/2019 20:44:48, 1555188288, Sensor 0, 21.2°C,").is_err());
+        assert!(parse_line("13/04/2019 20:44:48, 1555188288, S, 21.2°C, 631W").is_err());
+        assert!(parse_log_line("13/04/2019 20:44:48, 1555188288, , ,").is_err());
+        // extra fields were never written in the original format
+        let long_text = "13/04/2019 20:44:48, 1555188288, Sensor 0, 21.2°C, 631W, ch1 631W";
+        assert!(parse_line(long_text).is_err());
+    }
+
+    #[test]
+    fn reading_round_trips_through_log() {
+        let reading = CurrentCostReading {
+            timestamp: Utc.with_ymd_and_hms(2019, 8, 20, 15, 40, 42).unwrap(),
+            device: String::from("CC128-v1.29"),
+            sensor: 1,
+            temperature: 24.8,
+            power: 3600,
+            channels: vec![1000, 1200, 1400],
+            device_time: NaiveTime::from_hms_opt(10, 27, 59),
+            days_since_birth: Some(2353),
+            radio_id: Some(4066),
+            sensor_type: Some(1),
+        };
+
+        let Ok(LogLine::Reading(parsed)) = parse_log_line(&reading.to_log()) else {
+            panic!("Expected a reading");
+        };
+        assert_eq!(1566315642, parsed.timestamp);
+        assert_eq!("CC128-v1.29", parsed.device);
+        assert_eq!(1, parsed.sensor);
+        assert_eq!(24.8, parsed.temperature);
+        assert_eq!(3600, parsed.power);
+        assert_eq!(vec![1000, 1200, 1400], parsed.channels);
+        assert_eq!(Some(4066), parsed.radio_id);
+        assert_eq!(Some(1), parsed.sensor_type);
+        assert_eq!(Some(2353), parsed.days_since_birth);
+        assert_eq!(NaiveTime::from_hms_opt(10, 27, 59), parsed.device_time);
+    }
+
+    #[test]
+    fn impulse_and_history_round_trip_through_log() {
+        let impulse = ImpulseReading {
+            timestamp: Utc.with_ymd_and_hms(2019, 8, 20, 15, 40, 42).unwrap(),
+            device: String::from("CC128-v1.29"),
+            sensor: 9,
+            sensor_type: 2,
+            radio_id: None,
+            impulses: 89466,
+            impulses_per_unit: 1000,
+            rate: Some(3.6),
+        };
+        let expected = ImpulseLine {
+            timestamp: 1566315642,
+            device: String::from("CC128-v1.29"),
+            sensor: 9,
+            sensor_type: 2,
+            radio_id: None,
+            impulses: 89466,
+            impulses_per_unit: 1000,
+            rate: Some(3.6),
+        };
+        assert_eq!(
+            Ok(LogLine::Impulse(expected)),
+            parse_log_line(&impulse.to_log())
+        );
+
+        let history = HistoryRecord {
+            timestamp: Utc.with_ymd_and_hms(2019, 8, 20, 15, 40, 42).unwrap(),
+            device: String::from("CC128-v1.29"),
+            sensor: 0,
+            kind: HistoryKind::Monthly,
+            age: 3,
+            kwh: 597.25,
+        };
+        let expected = HistoryLine {
+            timestamp: 1566315642,
+            device: String::from("CC128-v1.29"),
+            sensor: 0,
+            kind: HistoryKind::Monthly,
+            age: 3,
+            kwh: 597.25,
+        };
+        assert_eq!(
+            Ok(LogLine::History(expected)),
+            parse_log_line(&history.to_log())
+        );
+    }
+
+    #[test]
+    fn legacy_and_current_formats_can_be_mixed() {
+        let sample_text = "13/04/2019 20:44:48, 1555188288, Sensor 0, 21.200000°C, 631W
+        {\"v\":2,\"record\":\"impulse\",\"timestamp\":1566315642,\"device\":\"CC128-v1.29\",\"sensor\":9,\"sensor_type\":2,\"impulses\":89466,\"impulses_per_unit\":1000}
+        {\"v\":2,\"record\":\"history\",\"timestamp\":1566315642,\"device\":\"CC128-v1.29\",\"sensor\":1,\"kind\":\"h\",\"age\":4,\"kwh\":1.799}
+        {\"v\":2,\"record\":\"reading\",\"timestamp\":1566315642,\"device\":\"CC128-v1.29\",\"sensor\":0,\"temperature\":24.8,\"power\":479,\"channels\":[479]}
+        {\"v\":3,\"record\":\"reading\",\"timestamp\":1566315643}
+        {\"v\":2,\"record\":\"reading\",\"timestamp\":1566315644}";
+
+        let parsed = parse_log(sample_text.lines().collect());
+
+        assert_eq!(2, parsed.readings.len());
+        assert_eq!(1555188288, parsed.readings[0].timestamp);
+        assert_eq!(1566315642, parsed.readings[1].timestamp);
+        assert_eq!(1, parsed.impulses.len());
+        assert_eq!(1, parsed.history.len());
+
+        assert_eq!(
+            Err("Unsupported log format version"),
+            parse_log_line("{\"v\":3,\"record\":\"reading\"}")
+        );
+    }
+
+    #[test]
+    fn log_gets_read_in_batches() {
+        let sample_text = "14/04/2019 23:25:26, 1555284326, Sensor 1, 22.100000°C, 0W
+        14/04/2019 23:25:29, 1555284329, Sensor 0, 22.100000°C, 544W
+        not a line
+        {\"v\":2,\"record\":\"history\",\"timestamp\":1566315642,\"device\":\"CC128-v1.29\",\"sensor\":1,\"kind\":\"h\",\"age\":4,\"kwh\":1.799}
+        14/04/2019 23:25:32, 1555284332, Sensor 1, 22.100000°C, 0W";
+
+        let batches: Vec<_> = LogBatches::new(sample_text.as_bytes(), 2)
+            .map(Result::unwrap)
+            .collect();
+
+        assert_eq!(3, batches.len());
+        assert_eq!(2, batches[0].readings.len());
+        assert_eq!(0, batches[1].readings.len());
+        assert_eq!(1, batches[1].history.len());
+        assert_eq!(1555284332, batches[2].readings[0].timestamp);
+    }
+}
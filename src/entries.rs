@@ -0,0 +1,248 @@
+//! Writing readings to the `entries` and `temperatures` tables, which both
+//! `store` and `connect`'s live database sink do.
+
+use chrono::{DateTime, Utc};
+use postgres::binary_copy::BinaryCopyInWriter;
+use postgres::types::Type;
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+
+use crate::CurrentcostLine;
+
+#[must_use]
+pub fn format_unixtime(timestamp: i32) -> DateTime<Utc> {
+    DateTime::from_timestamp(i64::from(timestamp), 0).unwrap()
+}
+
+/// Batches smaller than this are inserted a row at a time, as setting up a
+/// `COPY` costs more than it saves.
+const MIN_COPY_ROWS: usize = 100;
+
+const ENTRIES_COLUMNS: &str =
+    "device, sensor, datetime, power, channels, radio_id, sensor_type, days_since_birth, device_time";
+// re-importing a line updates it rather than adding a duplicate
+const ENTRIES_CONFLICT: &str = "ON CONFLICT (device, sensor, datetime) DO UPDATE SET power = EXCLUDED.power, channels = EXCLUDED.channels, radio_id = EXCLUDED.radio_id, sensor_type = EXCLUDED.sensor_type, days_since_birth = EXCLUDED.days_since_birth, device_time = EXCLUDED.device_time";
+const TEMPERATURES_CONFLICT: &str =
+    "ON CONFLICT (device, datetime) DO UPDATE SET temperature = EXCLUDED.temperature";
+
+/// Inserts `lines` into `entries`, and the temperatures they report into
+/// `temperatures`, in one transaction.
+pub fn insert_lines(
+    db_client: &mut postgres::Client,
+    lines: Vec<CurrentcostLine>,
+) -> Result<(), Box<dyn Error>> {
+    let mut transaction = db_client.transaction()?;
+    let lines = last_of_each_key(lines);
+    // every sensor reports the monitor's temperature, so only keep it once
+    let temperatures = temperatures_by_device(&lines);
+    if lines.len() < MIN_COPY_ROWS {
+        insert_entries(&mut transaction, &lines)?;
+        insert_temperatures(&mut transaction, &temperatures)?;
+    } else {
+        copy_entries(&mut transaction, &lines)?;
+        copy_temperatures(&mut transaction, &temperatures)?;
+    }
+
+    transaction.commit()?;
+    Ok(())
+}
+
+fn insert_entries(
+    transaction: &mut postgres::Transaction,
+    lines: &[CurrentcostLine],
+) -> Result<(), Box<dyn Error>> {
+    let query = format!(
+        "INSERT INTO entries ({ENTRIES_COLUMNS}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) {ENTRIES_CONFLICT}"
+    );
+    let prep_statement = transaction.prepare(&query)?;
+    for line in lines {
+        let unixtime = format_unixtime(line.timestamp);
+        transaction.execute(
+            &prep_statement,
+            &[
+                &line.device,
+                &line.sensor,
+                &unixtime,
+                &line.power,
+                &line.channels,
+                &line.radio_id,
+                &line.sensor_type,
+                &line.days_since_birth,
+                &line.device_time,
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+fn insert_temperatures(
+    transaction: &mut postgres::Transaction,
+    temperatures: &[(&str, i32, f32)],
+) -> Result<(), Box<dyn Error>> {
+    let query = format!(
+        "INSERT INTO temperatures (device, datetime, temperature) VALUES ($1, $2, $3) {TEMPERATURES_CONFLICT}"
+    );
+    let prep_statement = transaction.prepare(&query)?;
+    for (device, timestamp, temperature) in temperatures {
+        let unixtime = format_unixtime(*timestamp);
+        transaction.execute(&prep_statement, &[device, &unixtime, temperature])?;
+    }
+
+    Ok(())
+}
+
+/// Loads `lines` with a binary `COPY` into a temporary table first, since
+/// `COPY` itself can't update the rows that are already there.
+fn copy_entries(
+    transaction: &mut postgres::Transaction,
+    lines: &[CurrentcostLine],
+) -> Result<(), Box<dyn Error>> {
+    transaction.batch_execute(
+        "CREATE TEMPORARY TABLE entries_import (device text, sensor integer, datetime timestamptz, power integer, channels integer[], radio_id integer, sensor_type integer, days_since_birth integer, device_time time) ON COMMIT DROP",
+    )?;
+    let sink = transaction.copy_in(&format!(
+        "COPY entries_import ({ENTRIES_COLUMNS}) FROM STDIN BINARY"
+    ))?;
+    let mut writer = BinaryCopyInWriter::new(
+        sink,
+        &[
+            Type::TEXT,
+            Type::INT4,
+            Type::TIMESTAMPTZ,
+            Type::INT4,
+            Type::INT4_ARRAY,
+            Type::INT4,
+            Type::INT4,
+            Type::INT4,
+            Type::TIME,
+        ],
+    );
+    for line in lines {
+        let unixtime = format_unixtime(line.timestamp);
+        writer.write(&[
+            &line.device,
+            &line.sensor,
+            &unixtime,
+            &line.power,
+            &line.channels,
+            &line.radio_id,
+            &line.sensor_type,
+            &line.days_since_birth,
+            &line.device_time,
+        ])?;
+    }
+    writer.finish()?;
+
+    transaction.execute(
+        &format!(
+            "INSERT INTO entries ({ENTRIES_COLUMNS}) SELECT {ENTRIES_COLUMNS} FROM entries_import {ENTRIES_CONFLICT}"
+        ),
+        &[],
+    )?;
+    Ok(())
+}
+
+fn copy_temperatures(
+    transaction: &mut postgres::Transaction,
+    temperatures: &[(&str, i32, f32)],
+) -> Result<(), Box<dyn Error>> {
+    transaction.batch_execute(
+        "CREATE TEMPORARY TABLE temperatures_import (device text, datetime timestamptz, temperature real) ON COMMIT DROP",
+    )?;
+    let sink = transaction
+        .copy_in("COPY temperatures_import (device, datetime, temperature) FROM STDIN BINARY")?;
+    let mut writer = BinaryCopyInWriter::new(sink, &[Type::TEXT, Type::TIMESTAMPTZ, Type::FLOAT4]);
+    for (device, timestamp, temperature) in temperatures {
+        let unixtime = format_unixtime(*timestamp);
+        writer.write(&[device, &unixtime, temperature])?;
+    }
+    writer.finish()?;
+
+    transaction.execute(
+        &format!(
+            "INSERT INTO temperatures (device, datetime, temperature) SELECT device, datetime, temperature FROM temperatures_import {TEMPERATURES_CONFLICT}"
+        ),
+        &[],
+    )?;
+    Ok(())
+}
+
+/// Drops all but the last of the lines with the same device, sensor and
+/// timestamp, as an upsert can't change the same row twice.
+fn last_of_each_key(lines: Vec<CurrentcostLine>) -> Vec<CurrentcostLine> {
+    let mut keys = HashSet::new();
+    let mut lines: Vec<_> = lines
+        .into_iter()
+        .rev()
+        .filter(|line| keys.insert((line.device.clone(), line.sensor, line.timestamp)))
+        .collect();
+    lines.reverse();
+    lines
+}
+
+/// The temperature reported by each device at each timestamp, taken from
+/// whichever of its sensors' lines comes first.
+fn temperatures_by_device(lines: &[CurrentcostLine]) -> Vec<(&str, i32, f32)> {
+    let mut temperatures = BTreeMap::new();
+    for line in lines {
+        temperatures
+            .entry((line.device.as_str(), line.timestamp))
+            .or_insert(line.temperature);
+    }
+
+    temperatures
+        .into_iter()
+        .map(|((device, timestamp), temperature)| (device, timestamp, temperature))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::datalog::{parse_log, parse_log_line};
+    use crate::entries::{format_unixtime, last_of_each_key, temperatures_by_device};
+
+    #[test]
+    fn temperature_is_kept_once_per_device_and_timestamp() {
+        let sample_text = "11/08/2019 21:04:03, 1565557443, Sensor 0, 25.200000°C, 2637W
+        11/08/2019 21:04:03, 1565557443, Sensor 1, 25.300000°C, 12W
+        {\"v\":2,\"record\":\"reading\",\"timestamp\":1565557443,\"device\":\"CC128-v1.29\",\"sensor\":0,\"temperature\":24.8,\"power\":479,\"channels\":[479]}
+        {\"v\":2,\"record\":\"reading\",\"timestamp\":1565557443,\"device\":\"CC128-v1.29\",\"sensor\":2,\"temperature\":24.8,\"power\":21,\"channels\":[21]}
+        11/08/2019 21:04:09, 1565557449, Sensor 0, 25.400000°C, 2640W";
+        let parsed = parse_log(sample_text.lines().collect()).readings;
+
+        let temperatures = temperatures_by_device(&parsed);
+        assert_eq!(
+            vec![
+                ("", 1565557443, 25.2),
+                ("", 1565557449, 25.4),
+                ("CC128-v1.29", 1565557443, 24.8)
+            ],
+            temperatures
+        );
+
+        assert!(parse_log_line("11/08/2019 21:04:03, 1565557443, Sensor 0, 25.2F, 2637W").is_err());
+    }
+
+    #[test]
+    fn only_the_last_line_for_each_key_is_kept() {
+        let sample_text = "11/08/2019 21:04:03, 1565557443, Sensor 0, 25.200000°C, 2637W
+        11/08/2019 21:04:03, 1565557443, Sensor 1, 25.200000°C, 12W
+        11/08/2019 21:04:03, 1565557443, Sensor 0, 25.200000°C, 2640W
+        {\"v\":2,\"record\":\"reading\",\"timestamp\":1565557443,\"device\":\"CC128-v1.29\",\"sensor\":0,\"temperature\":24.8,\"power\":479,\"channels\":[479]}";
+        let parsed = parse_log(sample_text.lines().collect()).readings;
+
+        let lines = last_of_each_key(parsed);
+        let powers: Vec<_> = lines.iter().map(|line| line.power).collect();
+        assert_eq!(vec![12, 2640, 479], powers);
+    }
+
+    #[test]
+    fn max_datetime_formatted_correctly() {
+        let timestamp = 1711972202;
+        let time_string = "2024-04-01 11:50:02 UTC";
+
+        let result = format_unixtime(timestamp);
+        assert_eq!(time_string, result.to_string());
+    }
+}
@@ -0,0 +1,141 @@
+//! Splitting the bytes read from a device into messages, however the reads
+//! happen to divide them up.
+
+/// The most that's kept of a message that hasn't ended yet; the longest the
+/// devices send, with history, are a few kilobytes.
+pub const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+const END_TAG: &[u8] = b"</msg>";
+
+/// Collects bytes until they make up a message, which ends with a newline or
+/// `</msg>`, keeping whatever comes after it for the next one.
+pub struct Framer {
+    buffer: Vec<u8>,
+    max_len: usize,
+}
+
+impl Default for Framer {
+    fn default() -> Self {
+        Self::new(MAX_MESSAGE_LEN)
+    }
+}
+
+impl Framer {
+    /// A framer that gives up on a message once `max_len` bytes of it have
+    /// arrived without it ending.
+    #[must_use]
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            buffer: Vec::new(),
+            max_len,
+        }
+    }
+
+    /// Adds `bytes` to what's been received so far and returns the messages
+    /// they complete, exactly as they were sent, even if they aren't valid
+    /// UTF-8.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<Vec<u8>> {
+        self.buffer.extend_from_slice(bytes);
+
+        let mut messages = Vec::new();
+        while let Some(end) = message_end(&self.buffer) {
+            let message: Vec<u8> = self.buffer.drain(..end).collect();
+            // the newline after `</msg>` is left on its own
+            if !message.iter().all(u8::is_ascii_whitespace) {
+                messages.push(message);
+            }
+        }
+
+        if self.buffer.len() > self.max_len {
+            log::warn!(
+                "Discarding {} bytes received without the end of a message",
+                self.buffer.len()
+            );
+            self.buffer.clear();
+        }
+        messages
+    }
+}
+
+fn message_end(buffer: &[u8]) -> Option<usize> {
+    let newline = buffer
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .map(|index| index + 1);
+    let end_tag = buffer
+        .windows(END_TAG.len())
+        .position(|window| window == END_TAG)
+        .map(|index| index + END_TAG.len());
+
+    match (newline, end_tag) {
+        (Some(newline), Some(end_tag)) => Some(newline.min(end_tag)),
+        (newline, end_tag) => newline.or(end_tag),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::framing::Framer;
+
+    const STREAM: &str = "<msg><src>CC128-v1.29</src><tmpr>18.7</tmpr></msg>\r\n\
+        <msg><src>Caf\u{e9} \u{2013} EnviR</src><tmpr>19.0</tmpr></msg>\r\n\
+        garbage\n\
+        <msg><src>CC128-v1.29</src><tmpr>18.8</tmpr></msg>";
+
+    fn expected() -> Vec<Vec<u8>> {
+        [
+            "<msg><src>CC128-v1.29</src><tmpr>18.7</tmpr></msg>",
+            "<msg><src>Caf\u{e9} \u{2013} EnviR</src><tmpr>19.0</tmpr></msg>",
+            "garbage\n",
+            "<msg><src>CC128-v1.29</src><tmpr>18.8</tmpr></msg>",
+        ]
+        .iter()
+        .map(|message| message.as_bytes().to_vec())
+        .collect()
+    }
+
+    #[test]
+    fn messages_are_the_same_wherever_reads_split_them() {
+        let bytes = STREAM.as_bytes();
+        for first in 0..bytes.len() {
+            for second in first..bytes.len() {
+                let mut framer = Framer::default();
+                let mut messages = framer.push(&bytes[..first]);
+                messages.extend(framer.push(&bytes[first..second]));
+                messages.extend(framer.push(&bytes[second..]));
+                assert_eq!(expected(), messages, "split at {first} and {second}");
+            }
+        }
+    }
+
+    #[test]
+    fn messages_arrive_a_byte_at_a_time() {
+        let mut framer = Framer::default();
+        let messages: Vec<Vec<u8>> = STREAM
+            .as_bytes()
+            .iter()
+            .flat_map(|byte| framer.push(&[*byte]))
+            .collect();
+        assert_eq!(expected(), messages);
+    }
+
+    #[test]
+    fn invalid_utf8_is_kept() {
+        let mut framer = Framer::default();
+        assert_eq!(
+            vec![b"<msg>\xff</msg>".to_vec()],
+            framer.push(b"<msg>\xff</msg>")
+        );
+    }
+
+    #[test]
+    fn messages_that_never_end_are_discarded() {
+        let mut framer = Framer::new(16);
+        assert!(framer.push(b"<msg><src>CC128-v1.29").is_empty());
+        // the end of it turns up on its own, and gets rejected when it's parsed
+        assert_eq!(
+            vec![b"</src></msg>".to_vec(), b"<msg></msg>".to_vec()],
+            framer.push(b"</src></msg>\r\n<msg></msg>")
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, Timelike, Utc};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::reading::to_log_line;
+
+/// Which buckets a `<hist>` message holds, written to the history log as the
+/// letter the device names them with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryKind {
+    Hourly,
+    Daily,
+    Monthly,
+}
+
+impl HistoryKind {
+    #[must_use]
+    pub fn from_prefix(prefix: char) -> Option<Self> {
+        match prefix {
+            'h' => Some(Self::Hourly),
+            'd' => Some(Self::Daily),
+            'm' => Some(Self::Monthly),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn prefix(self) -> char {
+        match self {
+            Self::Hourly => 'h',
+            Self::Daily => 'd',
+            Self::Monthly => 'm',
+        }
+    }
+
+    /// When the bucket `age` back from `received` started, which stays the
+    /// same each time the device sends it again: hourly buckets start on an
+    /// even hour, daily ones at midnight and monthly ones on the 1st, in UTC.
+    #[must_use]
+    pub fn bucket_start(self, received: DateTime<Utc>, age: i32) -> DateTime<Utc> {
+        #![allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+        let midnight = received.date_naive().and_time(NaiveTime::MIN).and_utc();
+        match self {
+            Self::Hourly => {
+                let hour = received.hour() / 2 * 2;
+                midnight + Duration::hours(i64::from(hour) - i64::from(age))
+            }
+            Self::Daily => midnight - Duration::days(i64::from(age)),
+            Self::Monthly => {
+                let months = received.year() * 12 + received.month0() as i32 - age;
+                let month = months.rem_euclid(12) as u32 + 1;
+                NaiveDate::from_ymd_opt(months.div_euclid(12), month, 1)
+                    .expect("the 1st of a month")
+                    .and_time(NaiveTime::MIN)
+                    .and_utc()
+            }
+        }
+    }
+}
+
+/// One kWh bucket from a `<hist>` message: `age` counts back from the time
+/// the device sent it, in units of `kind` (hourly buckets cover two hours).
+impl Serialize for HistoryKind {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_char(self.prefix())
+    }
+}
+
+impl<'de> Deserialize<'de> for HistoryKind {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let prefix = char::deserialize(deserializer)?;
+        Self::from_prefix(prefix)
+            .ok_or_else(|| de::Error::invalid_value(de::Unexpected::Char(prefix), &"h, d or m"))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryRecord {
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub timestamp: chrono::DateTime<Utc>,
+    pub device: String,
+    pub sensor: i32,
+    pub kind: HistoryKind,
+    pub age: u32,
+    pub kwh: f32,
+}
+
+impl HistoryRecord {
+    #[must_use]
+    pub fn to_log(&self) -> String {
+        to_log_line("history", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::history::{HistoryKind, HistoryRecord};
+    use chrono::prelude::*;
+
+    #[test]
+    fn convert_history_record_to_log_line() {
+        let record = HistoryRecord {
+            timestamp: Utc.with_ymd_and_hms(2019, 8, 20, 15, 40, 42).unwrap(),
+            device: String::from("CC128-v1.29"),
+            sensor: 1,
+            kind: HistoryKind::Hourly,
+            age: 4,
+            kwh: 1.799,
+        };
+
+        let log_line = "{\"v\":2,\"record\":\"history\",\"timestamp\":1566315642,\"device\":\"CC128-v1.29\",\"sensor\":1,\"kind\":\"h\",\"age\":4,\"kwh\":1.799}\n";
+        assert_eq!(record.to_log(), log_line);
+    }
+
+    #[test]
+    fn buckets_sent_again_start_at_the_same_time() {
+        let received = |hour, min| Utc.with_ymd_and_hms(2019, 8, 20, hour, min, 0).unwrap();
+        let ten = received(10, 0);
+        assert_eq!(ten, HistoryKind::Hourly.bucket_start(received(15, 40), 4));
+        assert_eq!(ten, HistoryKind::Hourly.bucket_start(received(17, 41), 6));
+
+        assert_eq!(
+            Utc.with_ymd_and_hms(2019, 8, 19, 0, 0, 0).unwrap(),
+            HistoryKind::Daily.bucket_start(received(15, 40), 1)
+        );
+        assert_eq!(
+            Utc.with_ymd_and_hms(2018, 12, 1, 0, 0, 0).unwrap(),
+            HistoryKind::Monthly.bucket_start(received(15, 40), 8)
+        );
+    }
+
+    #[test]
+    fn history_kind_is_read_back_from_its_prefix() {
+        assert_eq!(
+            HistoryKind::Daily,
+            serde_json::from_str::<HistoryKind>("\"d\"").unwrap()
+        );
+        assert!(serde_json::from_str::<HistoryKind>("\"x\"").is_err());
+    }
+}
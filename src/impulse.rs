@@ -0,0 +1,95 @@
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::reading::to_log_line;
+
+/// A reading from an impulse sensor (`<type>` 2 or 3), such as an optical
+/// sensor on a gas or water meter, which counts pulses rather than watts.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImpulseReading {
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub timestamp: chrono::DateTime<Utc>,
+    pub device: String,
+    pub sensor: i32,
+    pub sensor_type: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub radio_id: Option<u32>,
+    /// The running total of pulses counted by the sensor.
+    pub impulses: u64,
+    /// Pulses per unit of whatever the meter measures, e.g. per kWh or m³.
+    pub impulses_per_unit: u32,
+    /// Units per hour since the previous reading from the same sensor.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate: Option<f64>,
+}
+
+impl ImpulseReading {
+    /// The consumption rate in units per hour between `previous` and this
+    /// reading, or `None` if it can't be worked out, e.g. because the pulse
+    /// count has gone backwards after the sensor was reset.
+    #[must_use]
+    pub fn rate_since(&self, previous: &Self) -> Option<f64> {
+        #![allow(clippy::cast_precision_loss)]
+        let elapsed = (self.timestamp - previous.timestamp).num_milliseconds();
+        if elapsed <= 0
+            || self.impulses < previous.impulses
+            || self.impulses_per_unit == 0
+            || self.impulses_per_unit != previous.impulses_per_unit
+        {
+            return None;
+        }
+
+        let units = (self.impulses - previous.impulses) as f64 / f64::from(self.impulses_per_unit);
+        let hours = elapsed as f64 / 3_600_000.0;
+        Some(units / hours)
+    }
+
+    #[must_use]
+    pub fn to_log(&self) -> String {
+        to_log_line("impulse", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::impulse::ImpulseReading;
+    use chrono::prelude::*;
+
+    fn impulse_reading(second: u32, impulses: u64) -> ImpulseReading {
+        ImpulseReading {
+            timestamp: Utc.with_ymd_and_hms(2019, 8, 20, 15, 40, second).unwrap(),
+            device: String::from("CC128-v1.29"),
+            sensor: 9,
+            sensor_type: 2,
+            radio_id: Some(1234),
+            impulses,
+            impulses_per_unit: 1000,
+            rate: None,
+        }
+    }
+
+    #[test]
+    fn convert_impulse_reading_to_log_line() {
+        let mut reading = impulse_reading(42, 89466);
+        let log_line = "{\"v\":2,\"record\":\"impulse\",\"timestamp\":1566315642,\"device\":\"CC128-v1.29\",\"sensor\":9,\"sensor_type\":2,\"radio_id\":1234,\"impulses\":89466,\"impulses_per_unit\":1000}\n";
+        assert_eq!(reading.to_log(), log_line);
+
+        reading.rate = Some(1.5);
+        let log_line = "{\"v\":2,\"record\":\"impulse\",\"timestamp\":1566315642,\"device\":\"CC128-v1.29\",\"sensor\":9,\"sensor_type\":2,\"radio_id\":1234,\"impulses\":89466,\"impulses_per_unit\":1000,\"rate\":1.5}\n";
+        assert_eq!(reading.to_log(), log_line);
+    }
+
+    #[test]
+    fn rate_gets_derived_from_previous_reading() {
+        let previous = impulse_reading(0, 89466);
+        // 6 pulses in 6 seconds at 1000 per unit is 3.6 units an hour
+        let current = impulse_reading(6, 89472);
+        let rate = current.rate_since(&previous).unwrap();
+        assert!((rate - 3.6).abs() < 1e-9);
+
+        let reset = impulse_reading(12, 3);
+        assert_eq!(None, reset.rate_since(&current));
+        assert_eq!(None, previous.rate_since(&current));
+    }
+}
@@ -1,75 +1,79 @@
+pub mod capture;
+pub mod config;
+pub mod datalog;
+pub mod entries;
+pub mod framing;
+pub mod history;
+pub mod impulse;
+pub mod protocol;
+pub mod reading;
+pub mod rfc2217;
+pub mod schema;
+pub mod sink;
+pub mod source;
+#[cfg(test)]
+mod test_support;
+
+use chrono::NaiveTime;
+use native_tls::TlsConnector;
+use postgres::config::SslMode;
 use postgres::NoTls;
+use postgres_native_tls::MakeTlsConnector;
+use serde::Deserialize;
 use std::cmp::Ordering;
-use std::fs;
-use std::path::Path;
+use std::error::Error;
 use std::process;
 
-use toml::Table;
-
-pub struct Config {
-    pub filename: String,
-    pub database: DatabaseConfig,
-}
-
-impl Config {
-    pub fn new(args: &[String]) -> Result<Self, &'static str> {
-        if args.len() < 2 {
-            return Err("not enough arguments");
-        }
-        let filename = args[1].clone();
-        let working_dir = get_path_to_bin_location(args);
-        let properties = fs::read_to_string(working_dir.join("config.toml"))
-            .unwrap_or_else(|_err| fs::read_to_string("config.toml").unwrap());
-        let values = &properties.parse::<Table>().unwrap();
-        let database_config = DatabaseConfig::new(&values["database"]).unwrap();
+use crate::history::HistoryKind;
 
-        Ok(Self {
-            filename,
-            database: database_config,
-        })
-    }
-}
+pub use crate::config::{Config, DatabaseConfig};
+pub use crate::reading::CurrentCostReading;
 
-pub struct DatabaseConfig {
-    ignore_db: bool,
-    database_name: String,
-    host: String,
-    user: String,
+#[must_use]
+pub fn get_db_connection(config: &Config) -> postgres::Client {
+    connect(&config.database).unwrap_or_else(|err| {
+        println!("Failed to connect to DB: {err}");
+        process::exit(1);
+    })
 }
 
-impl DatabaseConfig {
-    pub fn new(args: &toml::Value) -> Result<Self, &'static str> {
-        Ok(Self {
-            ignore_db: args["ignore_db"].as_bool().unwrap(),
-            database_name: String::from(args["db_name"].as_str().unwrap()),
-            host: String::from(args["hostname"].as_str().unwrap()),
-            user: String::from(args["user"].as_str().unwrap()),
-        })
+fn connect(database: &DatabaseConfig) -> Result<postgres::Client, Box<dyn Error>> {
+    if database.postgres.get_ssl_mode() == SslMode::Disable {
+        return Ok(database.postgres.connect(NoTls)?);
     }
 
-    #[must_use]
-    pub fn use_database(&self) -> bool {
-        !self.ignore_db
+    let mut connector = TlsConnector::builder();
+    if let Some(certificate) = &database.root_certificate {
+        connector.add_root_certificate(certificate.clone());
     }
+    let tls = MakeTlsConnector::new(connector.build()?);
+    Ok(database.postgres.connect(tls)?)
 }
 
-#[must_use]
-pub fn get_db_connection(config: &Config) -> postgres::Client {
-    postgres::config::Config::new()
-        .user(&config.database.user)
-        .host(&config.database.host)
-        .dbname(&config.database.database_name)
-        .connect(NoTls)
-        .unwrap_or_else(|err| {
-            println!("Failed to connect to DB: {err}");
-            process::exit(1);
-        })
+/// A line of the data log, as read back by `store`; in version 2 of the
+/// format the `record` field says which kind of line it is.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "record", rename_all = "lowercase")]
+pub enum LogLine {
+    Reading(CurrentcostLine),
+    Impulse(ImpulseLine),
+    History(HistoryLine),
 }
 
+#[derive(Debug, Deserialize)]
 pub struct CurrentcostLine {
     pub timestamp: i32,
+    /// Empty for lines in the original format, which didn't record the device.
+    #[serde(default)]
+    pub device: String,
     pub sensor: i32,
+    pub temperature: f32,
     pub power: i32,
+    pub channels: Vec<i32>,
+    pub radio_id: Option<i32>,
+    pub sensor_type: Option<i32>,
+    pub days_since_birth: Option<i32>,
+    pub device_time: Option<NaiveTime>,
 }
 impl PartialOrd for CurrentcostLine {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
@@ -85,14 +89,41 @@ impl Eq for CurrentcostLine {}
 impl PartialEq for CurrentcostLine {
     fn eq(&self, other: &Self) -> bool {
         self.timestamp == other.timestamp
+            && self.device == other.device
             && self.sensor == other.sensor
+            && self.temperature.to_bits() == other.temperature.to_bits()
             && self.power == other.power
+            && self.channels == other.channels
+            && self.radio_id == other.radio_id
+            && self.sensor_type == other.sensor_type
+            && self.days_since_birth == other.days_since_birth
+            && self.device_time == other.device_time
     }
 }
 
-fn get_path_to_bin_location(args: &[String]) -> &Path {
-    assert!(!args.is_empty());
-    let path = Path::new(&args[0]);
+/// A single kWh bucket from the history log: `kind` is one of `h`, `d` or `m`
+/// (hourly, daily, monthly) and `age` counts back from `timestamp`.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct HistoryLine {
+    pub timestamp: i32,
+    #[serde(default)]
+    pub device: String,
+    pub sensor: i32,
+    pub kind: HistoryKind,
+    pub age: i32,
+    pub kwh: f32,
+}
 
-    path.parent().unwrap_or_else(|| Path::new("."))
+/// An impulse sensor reading from the data log; `rate` is in units per hour.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct ImpulseLine {
+    pub timestamp: i32,
+    #[serde(default)]
+    pub device: String,
+    pub sensor: i32,
+    pub sensor_type: i32,
+    pub radio_id: Option<i32>,
+    pub impulses: i64,
+    pub impulses_per_unit: i32,
+    pub rate: Option<f64>,
 }
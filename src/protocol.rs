@@ -0,0 +1,733 @@
+//! Decoding of the XML messages sent by CurrentCost devices.
+//!
+//! Each message arrives on its own line, e.g.
+//! `<msg><src>CC128-v1.29</src>...<ch1><watts>00479</watts></ch1></msg>`, and is
+//! either a realtime reading, an impulse sensor reading or a block of history.
+
+use chrono::{DateTime, NaiveTime, Utc};
+use roxmltree::{Document, Node};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::history::{HistoryKind, HistoryRecord};
+use crate::impulse::ImpulseReading;
+use crate::reading::CurrentCostReading;
+
+#[derive(Debug)]
+pub enum Message {
+    Reading(CurrentCostReading),
+    Impulse(ImpulseReading),
+    History(Vec<HistoryRecord>),
+    /// A well-formed message that is neither a reading nor history.
+    Unknown,
+}
+
+impl Message {
+    /// Dates the message `received` rather than when it was parsed, e.g. for
+    /// one replayed from a capture.
+    pub fn set_received(&mut self, received: DateTime<Utc>) {
+        match self {
+            Self::Reading(reading) => reading.timestamp = received,
+            Self::Impulse(reading) => reading.timestamp = received,
+            Self::History(records) => {
+                for record in records {
+                    record.timestamp = received;
+                }
+            }
+            Self::Unknown => (),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The line isn't well-formed XML, e.g. a message cut off mid-transmission.
+    MalformedXml(String),
+    /// A required element is absent, or appears more than once.
+    MissingField(&'static str),
+    /// An element that should hold a number doesn't.
+    InvalidNumber { field: &'static str, text: String },
+    /// A well-formed message of a kind that can't be decoded.
+    UnsupportedMessage(String),
+}
+
+impl ParseError {
+    /// A short, stable name for the kind of error, for use in counters.
+    #[must_use]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::MalformedXml(_) => "malformed_xml",
+            Self::MissingField(_) => "missing_field",
+            Self::InvalidNumber { .. } => "invalid_number",
+            Self::UnsupportedMessage(_) => "unsupported_message",
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MalformedXml(err) => write!(f, "Error parsing XML: {err}"),
+            Self::MissingField(field) => write!(f, "No {field} value found in data"),
+            Self::InvalidNumber { field, text } => {
+                write!(
+                    f,
+                    "Invalid {field} value - couldn't parse {text:?} as a number"
+                )
+            }
+            Self::UnsupportedMessage(description) => {
+                write!(f, "Unsupported message: {description}")
+            }
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// The variants of the XML format sent by different CurrentCost models.
+///
+/// The Classic (CC02) describes itself with `<src><name>` and `<sver>`
+/// elements and reports its clock under `<date>`, while the CC128 and the
+/// EnviR use a plain `<src>CC128-vX.YY</src>`; the EnviR identifies itself
+/// with a `v0.` version and, unlike the CC128, can report in Fahrenheit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    Classic,
+    Cc128,
+    Envir,
+}
+
+impl Dialect {
+    /// Works out the dialect of a message from its `<src>` element.
+    fn detect(doc: &Document) -> Result<Self, ParseError> {
+        let Some(src) = doc.descendants().find(|n| n.has_tag_name("src")) else {
+            return Err(ParseError::MissingField("src"));
+        };
+
+        if src.children().any(|n| n.has_tag_name("name")) {
+            Ok(Self::Classic)
+        } else if src.text().is_some_and(|name| name.starts_with("CC128-v0.")) {
+            Ok(Self::Envir)
+        } else {
+            Ok(Self::Cc128)
+        }
+    }
+}
+
+impl FromStr for Dialect {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "classic" => Ok(Self::Classic),
+            "cc128" => Ok(Self::Cc128),
+            "envir" => Ok(Self::Envir),
+            _ => Err(format!("Unknown dialect {name:?}")),
+        }
+    }
+}
+
+/// Parses one line of device output into whichever kind of message it holds.
+///
+/// # Errors
+///
+/// Returns a `ParseError` if the line isn't valid XML or the message it holds
+/// is missing fields or has values that can't be parsed.
+pub fn parse_message(line: &str) -> Result<Message, ParseError> {
+    parse_message_as(line, None)
+}
+
+/// Parses one line of device output as `dialect`, or works the dialect out
+/// from the message itself if it's `None`.
+///
+/// # Errors
+///
+/// Returns a `ParseError` if the line isn't valid XML or the message it holds
+/// is missing fields or has values that can't be parsed.
+pub fn parse_message_as(line: &str, dialect: Option<Dialect>) -> Result<Message, ParseError> {
+    let doc = parse_xml(line)?;
+    let dialect = match dialect {
+        Some(dialect) => dialect,
+        None => Dialect::detect(&doc)?,
+    };
+
+    if doc.descendants().any(|n| n.has_tag_name("hist")) {
+        Ok(Message::History(history_from_xmldoc(&doc, dialect)?))
+    } else if doc.descendants().any(|n| n.has_tag_name("ch1")) {
+        Ok(Message::Reading(reading_from_xmldoc(&doc, dialect)?))
+    } else if doc.descendants().any(|n| n.has_tag_name("imp")) {
+        Ok(Message::Impulse(impulse_from_xmldoc(&doc, dialect)?))
+    } else {
+        source_from_xmldoc(&doc, dialect)?;
+        Ok(Message::Unknown)
+    }
+}
+
+fn parse_xml(line: &str) -> Result<Document<'_>, ParseError> {
+    Document::parse(line).map_err(|err| ParseError::MalformedXml(err.to_string()))
+}
+
+fn get_element_from_xmldoc(root: &Document, element_name: &str, expected_count: usize) -> String {
+    let nodes: Vec<Node> = root
+        .descendants()
+        .filter(|n| n.has_tag_name(element_name))
+        .collect();
+    if nodes.len() != expected_count {
+        return String::new();
+    }
+    assert_eq!(nodes.len(), expected_count);
+    let value = nodes[0].text().unwrap_or_default();
+
+    String::from(value)
+}
+
+fn get_required_element(root: &Document, element_name: &'static str) -> Result<String, ParseError> {
+    let value = get_element_from_xmldoc(root, element_name, 1);
+    if value.is_empty() {
+        Err(ParseError::MissingField(element_name))
+    } else {
+        Ok(value)
+    }
+}
+
+fn get_optional_element(root: &Document, element_name: &str) -> Option<String> {
+    Some(get_element_from_xmldoc(root, element_name, 1)).filter(|value| !value.is_empty())
+}
+
+/// An element that only describes the device or sensor, so a garbled value
+/// is logged and left out rather than losing the rest of the message.
+fn parse_optional_number<T: FromStr>(root: &Document, element_name: &'static str) -> Option<T> {
+    let value = get_optional_element(root, element_name)?;
+    let number = value.parse().ok();
+    if number.is_none() {
+        log::warn!("Ignoring invalid {element_name}: {value:?}");
+    }
+    number
+}
+
+fn parse_number<T: FromStr>(field: &'static str, text: &str) -> Result<T, ParseError> {
+    text.parse::<T>().map_err(|_err| ParseError::InvalidNumber {
+        field,
+        text: String::from(text),
+    })
+}
+
+fn get_channels_from_xmldoc(root: &Document) -> Result<Vec<i32>, ParseError> {
+    let mut channels = Vec::new();
+    // channels are numbered from ch1 and a CC128 sends at most three
+    for channel in 1..=3 {
+        let tag_name = format!("ch{channel}");
+        let Some(node) = root
+            .descendants()
+            .find(|n| n.has_tag_name(tag_name.as_str()))
+        else {
+            break;
+        };
+
+        let mut watts = node.children().filter(|n| n.has_tag_name("watts"));
+        let (Some(watts_node), None) = (watts.next(), watts.next()) else {
+            return Err(ParseError::MissingField("watts"));
+        };
+        channels.push(parse_number(
+            "watts",
+            watts_node.text().unwrap_or_default(),
+        )?);
+    }
+
+    Ok(channels)
+}
+
+/// The device name, e.g. `CC128-v1.29`, or `CC02-v0.07` for a Classic.
+fn source_from_xmldoc(doc: &Document, dialect: Dialect) -> Result<String, ParseError> {
+    match dialect {
+        Dialect::Classic => Ok(format!(
+            "{}-v{}",
+            get_required_element(doc, "name")?,
+            get_required_element(doc, "sver")?
+        )),
+        Dialect::Cc128 | Dialect::Envir => get_required_element(doc, "src"),
+    }
+}
+
+/// The temperature in Celsius, converting from `<tmprF>` where the device
+/// has been set to report in Fahrenheit.
+fn temperature_from_xmldoc(doc: &Document, dialect: Dialect) -> Result<f32, ParseError> {
+    if dialect != Dialect::Cc128 {
+        if let Some(fahrenheit) = get_optional_element(doc, "tmprF") {
+            let fahrenheit: f32 = parse_number("tmprF", &fahrenheit)?;
+            return Ok((fahrenheit - 32.0) * 5.0 / 9.0);
+        }
+    }
+
+    parse_number("tmpr", &get_required_element(doc, "tmpr")?)
+}
+
+/// The device's clock, which like `parse_optional_number`'s elements is left
+/// out if it's garbled.
+fn device_time_from_xmldoc(doc: &Document, dialect: Dialect) -> Option<NaiveTime> {
+    let time = match dialect {
+        // the Classic splits its clock into <hr>, <min> and <sec> under <date>
+        Dialect::Classic => match (
+            get_optional_element(doc, "hr"),
+            get_optional_element(doc, "min"),
+            get_optional_element(doc, "sec"),
+        ) {
+            (Some(hour), Some(minute), Some(second)) => format!("{hour}:{minute}:{second}"),
+            _ => return None,
+        },
+        Dialect::Cc128 | Dialect::Envir => get_optional_element(doc, "time")?,
+    };
+
+    let device_time = NaiveTime::parse_from_str(&time, "%H:%M:%S").ok();
+    if device_time.is_none() {
+        log::warn!("Ignoring invalid time: {time:?}");
+    }
+    device_time
+}
+
+fn sensor_from_xmldoc(doc: &Document, dialect: Dialect) -> Result<i32, ParseError> {
+    match dialect {
+        // the Classic only has the one sensor
+        Dialect::Classic => Ok(0),
+        Dialect::Cc128 | Dialect::Envir => {
+            parse_number("sensor", &get_required_element(doc, "sensor")?)
+        }
+    }
+}
+
+/// Parses a realtime reading, rejecting anything else the device sends.
+///
+/// # Errors
+///
+/// Returns a `ParseError` if the line isn't a complete, valid reading.
+pub fn parse_line_from_device(line: &str) -> Result<CurrentCostReading, ParseError> {
+    let doc = parse_xml(line)?;
+    reading_from_xmldoc(&doc, Dialect::detect(&doc)?)
+}
+
+fn reading_from_xmldoc(doc: &Document, dialect: Dialect) -> Result<CurrentCostReading, ParseError> {
+    let source = source_from_xmldoc(doc, dialect)?;
+
+    let sensor_type: Option<u8> = parse_optional_number(doc, "type");
+    if let Some(sensor_type) = sensor_type.filter(|&sensor_type| sensor_type != 1) {
+        return Err(ParseError::UnsupportedMessage(format!(
+            "sensor type {sensor_type}"
+        )));
+    }
+
+    let channels = get_channels_from_xmldoc(doc)?;
+    if channels.is_empty() {
+        return Err(ParseError::MissingField("watts"));
+    }
+    let power = channels.iter().sum();
+
+    let temperature = temperature_from_xmldoc(doc, dialect)?;
+    let sensor = sensor_from_xmldoc(doc, dialect)?;
+
+    let device_time = device_time_from_xmldoc(doc, dialect);
+    let days_since_birth = parse_optional_number(doc, "dsb");
+    let radio_id = parse_optional_number(doc, "id");
+
+    let reading = CurrentCostReading {
+        timestamp: chrono::Utc::now(),
+        device: source,
+        sensor,
+        temperature,
+        power,
+        channels,
+        device_time,
+        days_since_birth,
+        radio_id,
+        sensor_type,
+    };
+
+    Ok(reading)
+}
+
+fn impulse_from_xmldoc(doc: &Document, dialect: Dialect) -> Result<ImpulseReading, ParseError> {
+    let source = source_from_xmldoc(doc, dialect)?;
+
+    let sensor_type = parse_number("type", &get_required_element(doc, "type")?)?;
+    if sensor_type != 2 && sensor_type != 3 {
+        return Err(ParseError::UnsupportedMessage(format!(
+            "impulses from sensor type {sensor_type}"
+        )));
+    }
+
+    let reading = ImpulseReading {
+        timestamp: chrono::Utc::now(),
+        device: source,
+        sensor: sensor_from_xmldoc(doc, dialect)?,
+        sensor_type,
+        radio_id: parse_optional_number(doc, "id"),
+        impulses: parse_number("imp", &get_required_element(doc, "imp")?)?,
+        impulses_per_unit: parse_number("ipu", &get_required_element(doc, "ipu")?)?,
+        rate: None,
+    };
+
+    Ok(reading)
+}
+
+/// Parses the kWh buckets from a `<hist>` message.
+///
+/// # Errors
+///
+/// Returns a `ParseError` if the line isn't a valid history message.
+pub fn parse_history_from_device(line: &str) -> Result<Vec<HistoryRecord>, ParseError> {
+    let doc = parse_xml(line)?;
+    history_from_xmldoc(&doc, Dialect::detect(&doc)?)
+}
+
+fn history_from_xmldoc(doc: &Document, dialect: Dialect) -> Result<Vec<HistoryRecord>, ParseError> {
+    let source = source_from_xmldoc(doc, dialect)?;
+
+    let Some(hist) = doc.descendants().find(|n| n.has_tag_name("hist")) else {
+        return Err(ParseError::MissingField("hist"));
+    };
+
+    let timestamp = chrono::Utc::now();
+    let mut records = Vec::new();
+    if dialect == Dialect::Classic {
+        // the Classic groups its buckets under <hrs>, <days> and <mths>
+        for group in hist.children().filter(Node::is_element) {
+            records.extend(buckets_from_node(group, &source, 0, timestamp)?);
+        }
+        return Ok(records);
+    }
+
+    for data in hist.children().filter(|n| n.has_tag_name("data")) {
+        let Some(sensor) = data
+            .children()
+            .find(|n| n.has_tag_name("sensor"))
+            .and_then(|n| n.text())
+        else {
+            return Err(ParseError::MissingField("sensor"));
+        };
+        let sensor = parse_number("sensor", sensor)?;
+
+        records.extend(buckets_from_node(data, &source, sensor, timestamp)?);
+    }
+
+    Ok(records)
+}
+
+fn buckets_from_node(
+    node: Node,
+    source: &str,
+    sensor: i32,
+    timestamp: chrono::DateTime<chrono::Utc>,
+) -> Result<Vec<HistoryRecord>, ParseError> {
+    let mut records = Vec::new();
+    // buckets are named by kind and age, e.g. <h004>, <d001>, <m012>
+    for bucket in node.children().filter(Node::is_element) {
+        let name = bucket.tag_name().name();
+        let mut name_chars = name.chars();
+        let Some(kind) = name_chars.next().and_then(HistoryKind::from_prefix) else {
+            continue;
+        };
+        let Ok(age) = name_chars.as_str().parse::<u32>() else {
+            continue;
+        };
+        let kwh = parse_number("kWh", bucket.text().unwrap_or_default())?;
+
+        records.push(HistoryRecord {
+            timestamp,
+            device: String::from(source),
+            sensor,
+            kind,
+            age,
+            kwh,
+        });
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_history_from_device;
+    use super::parse_line_from_device;
+    use super::parse_message;
+    use super::parse_message_as;
+    use super::Dialect;
+    use super::Message;
+    use super::ParseError;
+    use crate::history::HistoryKind;
+    use chrono::NaiveTime;
+
+    #[test]
+    fn line_gets_parsed() {
+        let sample_text = " <msg><src>CC128-v1.29</src><dsb>02353</dsb><time>10:27:59</time><tmpr>21.4</tmpr><sensor>0</sensor><id>04066</id><type>1</type><ch1><watts>00479</watts></ch1></msg>";
+        let parsed = parse_line_from_device(sample_text).unwrap();
+
+        //assert_eq!(1555188288, parsed.timestamp);
+        assert_eq!("CC128-v1.29", parsed.device);
+        assert_eq!(0, parsed.sensor);
+        assert_eq!(479, parsed.power);
+        assert_eq!(vec![479], parsed.channels);
+        assert_eq!(21.4, parsed.temperature);
+        assert_eq!(NaiveTime::from_hms_opt(10, 27, 59), parsed.device_time);
+        assert_eq!(Some(2353), parsed.days_since_birth);
+        assert_eq!(Some(4066), parsed.radio_id);
+        assert_eq!(Some(1), parsed.sensor_type);
+    }
+
+    #[test]
+    fn three_phase_line_gets_parsed() {
+        let sample_text = "<msg><src>CC128-v1.29</src><dsb>02353</dsb><time>10:27:59</time><tmpr>21.4</tmpr><sensor>0</sensor><id>04066</id><type>1</type><ch1><watts>00479</watts></ch1><ch2><watts>01200</watts></ch2><ch3><watts>00021</watts></ch3></msg>";
+        let parsed = parse_line_from_device(sample_text).unwrap();
+
+        assert_eq!(0, parsed.sensor);
+        assert_eq!(1700, parsed.power);
+        assert_eq!(vec![479, 1200, 21], parsed.channels);
+    }
+
+    #[test]
+    fn invalid_lines_return_errors() {
+        let mut sample_text = "<msg><src>CC128-v1.29</src><dsb>02353</dsb><time>10:27:59</time><tmpr>21.4</tmpr><sensor>0</sensor><id>04066</id><type>1</type><ch1><watts>p</watts></ch1></msg>";
+        let parse_result = parse_line_from_device(sample_text);
+
+        assert!(parse_result.is_err());
+
+        sample_text = "<msg><src>CC128-v1.29</src><dsb>02353</dsb><time>10:27:59</time><tmpr>2a.4</tmpr><sensor>0</sensor><id>04066</id><type>1</type><ch1><watts>00479</watts></ch1></msg>";
+        let parse_result = parse_line_from_device(sample_text);
+        assert!(parse_result.is_err());
+
+        sample_text = "<msg><src>CC128-v1.29</src><dsb>02353</dsb><time>10:27:59</time><tmpr>21.4</tmpr><sensor>0</sensor><id>04066</id><type>1</type><ch1><watts>00479</watts></ch1><ch2><watts>p</watts></ch2></msg>";
+        let parse_result = parse_line_from_device(sample_text);
+        assert!(parse_result.is_err());
+
+        sample_text = "<msg><src>CC128-v1.29</src><dsb>02353</dsb><time>10:27:59</time><tmpr>20.4</tmpr><sensor>p</sensor><id>04066</id><type>1</type><ch1><watts>00479</watts></ch1></msg>";
+        let parse_result = parse_line_from_device(sample_text);
+        assert!(parse_result.is_err());
+    }
+
+    #[test]
+    fn history_line_gets_ignored() {
+        let sample_text = "<msg><src>CC128-v1.29</src><dsb>02371</dsb><time>09:23:30</time><hist><dsw>02373</dsw><type>1</type><units>kwhr</units><data><sensor>0</sensor><m003>597.250</m003><m002>681.250</m002><m001>613.250</m001></data><data><sensor>1</sensor><m003>4.750</m003><m002>2.250</m002><m001>2.000</m001></data><data><sensor>2</sensor><m003>0.000</m003><m002>0.000</m002><m001>0.000</m001></data><data><sensor>3</sensor><m003>0.000</m003><m002>0.000</m002><m001>0.000</m001></data><data><sensor>4</sensor><m003>0.000</m003><m002>0.000</m002><m001>0.000</m001></data><data><sensor>5</sensor><m003>0.000</m003><m002>0.000</m002><m001>0.000</m001></data><data><sensor>6</sensor><m003>0.000</m003><m002>0.000</m002><m001>0.000</m001></data><data><sensor>7</sensor><m003>0.000</m003><m002>0.000</m002><m001>0.000</m001></data><data><sensor>8</sensor><m003>0.000</m003><m002>0.000</m002><m001>0.000</m001></data><data><sensor>9</sensor><m003>0.000</m003><m002>0.000</m002><m001>0.000</m001></data></hist></msg>";
+        let parse_result = parse_line_from_device(sample_text);
+        assert!(parse_result.is_err());
+    }
+
+    #[test]
+    fn history_line_gets_ignored_again() {
+        let sample_text = "<msg><src>CC128-v1.29</src><dsb>02371</dsb><time>23:01:20</time><hist><dsw>02373</dsw><type>1</type><units>kwhr</units><data><sensor>0</sensor><h730>1.799</h730><h728>1.553</h728><h726>2.986</h726><h724>1.125</h724></data><data><sensor>1</sensor><h730>0.000</h730><h728>0.000</h728><h726>0.023</h726><h724>0.000</h724></data><data><sensor>2</sensor><h730>0.000</h730><h728>0.000</h728><h726>0.000</h726><h724>0.000</h724></data><data><sensor>3</sensor><h730>0.000</h730><h728>0.000</h728><h726>0.000</h726><h724>0.000</h724></data><data><sensor>4</sensor><h730>0.000</h730><h728>0.000</h728><h726>0.000</h726><h724>0.000</h724></data><data><sensor>5</sensor><h730>0.000</h730><h728>0.000</h728><h726>0.000</h726><h724>0.000</h724></data><data><sensor>6</sensor><h730>0.000</h730><h728>0.000</h728><h726>0.000</h726><h724>0.000</h724></data><data><sensor>7</sensor><h730>0.000</h730><h728>0.000</h728><h726>0.000</h726><h724>0.000</h724></data><data><sensor>8</sensor><h730>0.000</h730><h728>0.000</h728><h726>0.000</h726><h724>0.000</h724></data><data><sensor>9</sensor><h730>0.000</h730><h728>0.000</h728><h726>0.000</h726><h724>0.000</h724></data></hist></msg>\n<msg>";
+        let parse_result = parse_line_from_device(sample_text);
+        assert!(parse_result.is_err());
+    }
+
+    #[test]
+    fn history_line_gets_parsed() {
+        let sample_text = "<msg><src>CC128-v1.29</src><dsb>02371</dsb><time>09:23:30</time><hist><dsw>02373</dsw><type>1</type><units>kwhr</units><data><sensor>0</sensor><m003>597.250</m003><m002>681.250</m002><m001>613.250</m001></data><data><sensor>1</sensor><m003>4.750</m003><m002>2.250</m002><m001>2.000</m001></data></hist></msg>";
+        let records = parse_history_from_device(sample_text).unwrap();
+
+        assert_eq!(6, records.len());
+        assert_eq!("CC128-v1.29", records[0].device);
+        assert_eq!(0, records[0].sensor);
+        assert_eq!(HistoryKind::Monthly, records[0].kind);
+        assert_eq!(3, records[0].age);
+        assert_eq!(597.25, records[0].kwh);
+        assert_eq!(1, records[5].sensor);
+        assert_eq!(1, records[5].age);
+        assert_eq!(2.0, records[5].kwh);
+    }
+
+    #[test]
+    fn hourly_history_line_gets_parsed() {
+        let sample_text = "<msg><src>CC128-v1.29</src><dsb>02371</dsb><time>23:01:20</time><hist><dsw>02373</dsw><type>1</type><units>kwhr</units><data><sensor>0</sensor><h730>1.799</h730><h728>1.553</h728></data></hist></msg>";
+        let records = parse_history_from_device(sample_text).unwrap();
+
+        assert_eq!(2, records.len());
+        assert_eq!(HistoryKind::Hourly, records[1].kind);
+        assert_eq!(728, records[1].age);
+        assert_eq!(1.553, records[1].kwh);
+    }
+
+    #[test]
+    fn reading_line_is_not_history() {
+        let sample_text = "<msg><src>CC128-v1.29</src><dsb>02353</dsb><time>10:27:59</time><tmpr>21.4</tmpr><sensor>0</sensor><id>04066</id><type>1</type><ch1><watts>00479</watts></ch1></msg>";
+        assert!(parse_history_from_device(sample_text).is_err());
+
+        let invalid_text = "<msg><src>CC128-v1.29</src><hist><data><sensor>0</sensor><h004>x</h004></data></hist></msg>";
+        assert!(parse_history_from_device(invalid_text).is_err());
+    }
+
+    #[test]
+    fn messages_get_classified() {
+        let reading = "<msg><src>CC128-v1.29</src><dsb>02353</dsb><time>10:27:59</time><tmpr>21.4</tmpr><sensor>0</sensor><id>04066</id><type>1</type><ch1><watts>00479</watts></ch1></msg>";
+        assert!(matches!(parse_message(reading), Ok(Message::Reading(_))));
+
+        let history = "<msg><src>CC128-v1.29</src><dsb>02371</dsb><time>09:23:30</time><hist><dsw>02373</dsw><type>1</type><units>kwhr</units><data><sensor>0</sensor><m001>613.250</m001></data></hist></msg>";
+        assert!(matches!(parse_message(history), Ok(Message::History(_))));
+
+        let unknown = "<msg><src>CC128-v1.29</src><dsb>02371</dsb><time>09:23:30</time></msg>";
+        assert!(matches!(parse_message(unknown), Ok(Message::Unknown)));
+
+        assert!(parse_message("<msg><src>CC128").is_err());
+        assert!(parse_message("<msg><time>09:23:30</time></msg>").is_err());
+    }
+
+    #[test]
+    fn impulse_line_gets_parsed() {
+        let sample_text = "<msg><src>CC128-v1.29</src><dsb>00089</dsb><time>13:10:50</time><tmpr>18.7</tmpr><sensor>9</sensor><id>01234</id><type>2</type><imp>0000089466</imp><ipu>1000</ipu></msg>";
+        let Ok(Message::Impulse(parsed)) = parse_message(sample_text) else {
+            panic!("Expected an impulse reading");
+        };
+
+        assert_eq!("CC128-v1.29", parsed.device);
+        assert_eq!(9, parsed.sensor);
+        assert_eq!(2, parsed.sensor_type);
+        assert_eq!(Some(1234), parsed.radio_id);
+        assert_eq!(89466, parsed.impulses);
+        assert_eq!(1000, parsed.impulses_per_unit);
+        assert_eq!(None, parsed.rate);
+
+        let missing_ipu = "<msg><src>CC128-v1.29</src><sensor>9</sensor><type>3</type><imp>0000089466</imp></msg>";
+        assert_eq!(
+            ParseError::MissingField("ipu"),
+            parse_message(missing_ipu).unwrap_err()
+        );
+
+        let electricity_type = "<msg><src>CC128-v1.29</src><sensor>9</sensor><type>1</type><imp>0000089466</imp><ipu>1000</ipu></msg>";
+        assert!(matches!(
+            parse_message(electricity_type),
+            Err(ParseError::UnsupportedMessage(_))
+        ));
+    }
+
+    #[test]
+    fn errors_describe_what_went_wrong() {
+        let truncated = "<msg><src>CC128-v1.29</src><tmpr>21.4";
+        assert!(matches!(
+            parse_message(truncated),
+            Err(ParseError::MalformedXml(_))
+        ));
+
+        let no_sensor =
+            "<msg><src>CC128-v1.29</src><tmpr>21.4</tmpr><ch1><watts>00479</watts></ch1></msg>";
+        assert_eq!(
+            Err(ParseError::MissingField("sensor")),
+            parse_line_from_device(no_sensor).map(|_| ())
+        );
+
+        let bad_power = "<msg><src>CC128-v1.29</src><tmpr>21.4</tmpr><sensor>0</sensor><ch1><watts>4x9</watts></ch1></msg>";
+        let err = parse_line_from_device(bad_power).unwrap_err();
+        assert_eq!(
+            ParseError::InvalidNumber {
+                field: "watts",
+                text: String::from("4x9")
+            },
+            err
+        );
+        assert_eq!("invalid_number", err.kind());
+
+        // the device's own details are left out if they're garbled
+        let bad_details = "<msg><src>CC128-v1.29</src><dsb>0x089</dsb><time>10:27:5x</time><tmpr>21.4</tmpr><sensor>0</sensor><id>4o66</id><type>1</type><ch1><watts>00479</watts></ch1></msg>";
+        let reading = parse_line_from_device(bad_details).unwrap();
+        assert_eq!(479, reading.power);
+        assert_eq!(None, reading.device_time);
+        assert_eq!(None, reading.days_since_birth);
+        assert_eq!(None, reading.radio_id);
+        assert_eq!(Some(1), reading.sensor_type);
+
+        let gas = "<msg><src>CC128-v1.29</src><tmpr>21.4</tmpr><sensor>3</sensor><type>2</type><ch1><watts>00479</watts></ch1></msg>";
+        assert!(matches!(
+            parse_line_from_device(gas),
+            Err(ParseError::UnsupportedMessage(_))
+        ));
+    }
+
+    const CLASSIC_READING: &str = "<msg><date><dsb>00014</dsb><hr>14</hr><min>07</min><sec>07</sec></date><src><name>CC02</name><id>03280</id><type>1</type><sver>0.07</sver></src><ch1><watts>00080</watts></ch1><ch2><watts>00000</watts></ch2><ch3><watts>00000</watts></ch3><tmpr>19.3</tmpr></msg>";
+    const CLASSIC_FAHRENHEIT_READING: &str = "<msg><date><dsb>00014</dsb><hr>14</hr><min>07</min><sec>13</sec></date><src><name>CC02</name><id>03280</id><type>1</type><sver>0.07</sver></src><ch1><watts>00082</watts></ch1><ch2><watts>00000</watts></ch2><ch3><watts>00000</watts></ch3><tmprF>66.2</tmprF></msg>";
+    const CLASSIC_HISTORY: &str = "<msg><date><dsb>00014</dsb><hr>14</hr><min>10</min><sec>00</sec></date><src><name>CC02</name><id>03280</id><type>1</type><sver>0.07</sver></src><hist><hrs><h02>000.1</h02><h04>000.3</h04></hrs><days><d01>0003</d01></days><mths><m01>0000</m01></mths><yrs><y1>0000000</y1></yrs></hist></msg>";
+    const CC128_READING: &str = "<msg><src>CC128-v1.29</src><dsb>02353</dsb><time>10:27:59</time><tmpr>21.4</tmpr><sensor>0</sensor><id>04066</id><type>1</type><ch1><watts>00479</watts></ch1></msg>";
+    const ENVIR_READING: &str = "<msg><src>CC128-v0.11</src><dsb>00089</dsb><time>13:02:39</time><tmpr>18.7</tmpr><sensor>1</sensor><id>00077</id><type>1</type><ch1><watts>00345</watts></ch1><ch2><watts>02151</watts></ch2><ch3><watts>00000</watts></ch3></msg>";
+    const ENVIR_FAHRENHEIT_READING: &str = "<msg><src>CC128-v0.11</src><dsb>00089</dsb><time>13:02:45</time><tmprF>72.5</tmprF><sensor>1</sensor><id>00077</id><type>1</type><ch1><watts>00346</watts></ch1><ch2><watts>02150</watts></ch2><ch3><watts>00000</watts></ch3></msg>";
+
+    fn parse_reading(line: &str, dialect: Option<Dialect>) -> crate::reading::CurrentCostReading {
+        match parse_message_as(line, dialect) {
+            Ok(Message::Reading(reading)) => reading,
+            other => panic!("Expected a reading, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn classic_reading_gets_parsed() {
+        let parsed = parse_reading(CLASSIC_READING, None);
+
+        assert_eq!("CC02-v0.07", parsed.device);
+        assert_eq!(0, parsed.sensor);
+        assert_eq!(80, parsed.power);
+        assert_eq!(vec![80, 0, 0], parsed.channels);
+        assert_eq!(19.3, parsed.temperature);
+        assert_eq!(NaiveTime::from_hms_opt(14, 7, 7), parsed.device_time);
+        assert_eq!(Some(14), parsed.days_since_birth);
+        assert_eq!(Some(3280), parsed.radio_id);
+
+        let parsed = parse_reading(CLASSIC_FAHRENHEIT_READING, Some(Dialect::Classic));
+        assert!((parsed.temperature - 19.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn classic_history_gets_parsed() {
+        let Ok(Message::History(records)) = parse_message(CLASSIC_HISTORY) else {
+            panic!("Expected history");
+        };
+
+        assert_eq!(4, records.len());
+        assert_eq!("CC02-v0.07", records[0].device);
+        assert_eq!(0, records[0].sensor);
+        assert_eq!(HistoryKind::Hourly, records[1].kind);
+        assert_eq!(4, records[1].age);
+        assert_eq!(0.3, records[1].kwh);
+        assert_eq!(HistoryKind::Daily, records[2].kind);
+        assert_eq!(HistoryKind::Monthly, records[3].kind);
+    }
+
+    #[test]
+    fn cc128_reading_gets_parsed() {
+        let parsed = parse_reading(CC128_READING, Some(Dialect::Cc128));
+
+        assert_eq!("CC128-v1.29", parsed.device);
+        assert_eq!(479, parsed.power);
+        assert_eq!(21.4, parsed.temperature);
+    }
+
+    #[test]
+    fn envir_reading_gets_parsed() {
+        let parsed = parse_reading(ENVIR_READING, None);
+
+        assert_eq!("CC128-v0.11", parsed.device);
+        assert_eq!(1, parsed.sensor);
+        assert_eq!(2496, parsed.power);
+        assert_eq!(18.7, parsed.temperature);
+
+        let parsed = parse_reading(ENVIR_FAHRENHEIT_READING, None);
+        assert_eq!(22.5, parsed.temperature);
+        assert_eq!(NaiveTime::from_hms_opt(13, 2, 45), parsed.device_time);
+    }
+
+    #[test]
+    fn dialects_get_detected_and_can_be_forced() {
+        assert_eq!(Ok(Dialect::Classic), "classic".parse());
+        assert_eq!(Ok(Dialect::Cc128), "CC128".parse());
+        assert_eq!(Ok(Dialect::Envir), "EnviR".parse::<Dialect>());
+        assert!("cc02".parse::<Dialect>().is_err());
+
+        // a Classic message can't be read as a CC128 one, and vice versa
+        assert_eq!(
+            ParseError::MissingField("src"),
+            parse_message_as(CLASSIC_READING, Some(Dialect::Cc128)).unwrap_err()
+        );
+        assert_eq!(
+            ParseError::MissingField("name"),
+            parse_message_as(CC128_READING, Some(Dialect::Classic)).unwrap_err()
+        );
+        // the CC128 doesn't report in Fahrenheit
+        assert_eq!(
+            ParseError::MissingField("tmpr"),
+            parse_message_as(ENVIR_FAHRENHEIT_READING, Some(Dialect::Cc128)).unwrap_err()
+        );
+    }
+}
@@ -1,25 +1,60 @@
-use chrono::Utc;
+use chrono::{NaiveTime, Utc};
+use serde::Serialize;
 
-#[derive(Debug)]
+/// The version written in the `v` field of each line of the data log.
+///
+/// Version 1 was the original comma-separated format,
+/// `"dd/mm/YYYY HH:MM:SS, epoch, Sensor N, T°C, PW"`, which had no version
+/// field; version 2 is one JSON object per line.
+pub const LOG_FORMAT_VERSION: u32 = 2;
+
+#[derive(Serialize)]
+struct LogEntry<'a, T> {
+    v: u32,
+    record: &'static str,
+    #[serde(flatten)]
+    value: &'a T,
+}
+
+/// Serializes `value` as one line of the data log, tagged with the format
+/// version and the kind of `record` it holds.
+pub(crate) fn to_log_line<T: Serialize>(record: &'static str, value: &T) -> String {
+    let entry = LogEntry {
+        v: LOG_FORMAT_VERSION,
+        record,
+        value,
+    };
+    let mut line = serde_json::to_string(&entry).expect("log entries are always serializable");
+    line.push('\n');
+    line
+}
+
+#[derive(Debug, Serialize)]
 pub struct CurrentCostReading {
+    #[serde(with = "chrono::serde::ts_seconds")]
     pub timestamp: chrono::DateTime<Utc>,
     pub device: String,
     pub sensor: i32,
     pub temperature: f32,
     pub power: i32,
+    pub channels: Vec<i32>,
+    /// The device's own clock, which has no date and drifts from the host's.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_time: Option<NaiveTime>,
+    /// Days since the monitor was first powered on; this resets with the monitor.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub days_since_birth: Option<u32>,
+    /// The transmitter's radio ID, which changes if a sensor slot is re-paired.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub radio_id: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sensor_type: Option<u8>,
 }
 
 impl CurrentCostReading {
     #[must_use]
     pub fn to_log(&self) -> String {
-        format!(
-            "{}, {}, Sensor {}, {:.2}\u{b0}C, {}W\n",
-            self.timestamp.format("%d/%m/%Y %H:%M:%S"),
-            self.timestamp.timestamp(),
-            self.sensor,
-            self.temperature,
-            self.power
-        )
+        to_log_line("reading", self)
     }
 }
 
@@ -37,9 +72,52 @@ mod tests {
             sensor: 0,
             temperature: 24.8,
             power: 3000,
+            channels: vec![3000],
+            device_time: None,
+            days_since_birth: None,
+            radio_id: None,
+            sensor_type: None,
+        };
+
+        let log_line = "{\"v\":2,\"record\":\"reading\",\"timestamp\":1566315642,\"device\":\"CC128-v1.29\",\"sensor\":0,\"temperature\":24.8,\"power\":3000,\"channels\":[3000]}\n";
+        assert_eq!(reading.to_log(), log_line);
+    }
+
+    #[test]
+    fn convert_multichannel_reading_to_log_line() {
+        let reading = CurrentCostReading {
+            timestamp: Utc.with_ymd_and_hms(2019, 8, 20, 15, 40, 42).unwrap(),
+            device: String::from("CC128-v1.29"),
+            sensor: 0,
+            temperature: 24.8,
+            power: 3600,
+            channels: vec![1000, 1200, 1400],
+            device_time: None,
+            days_since_birth: None,
+            radio_id: None,
+            sensor_type: None,
+        };
+
+        let log_line = "{\"v\":2,\"record\":\"reading\",\"timestamp\":1566315642,\"device\":\"CC128-v1.29\",\"sensor\":0,\"temperature\":24.8,\"power\":3600,\"channels\":[1000,1200,1400]}\n";
+        assert_eq!(reading.to_log(), log_line);
+    }
+
+    #[test]
+    fn convert_reading_with_device_fields_to_log_line() {
+        let reading = CurrentCostReading {
+            timestamp: Utc.with_ymd_and_hms(2019, 8, 20, 15, 40, 42).unwrap(),
+            device: String::from("CC128-v1.29"),
+            sensor: 0,
+            temperature: 24.8,
+            power: 479,
+            channels: vec![479],
+            device_time: NaiveTime::from_hms_opt(10, 27, 59),
+            days_since_birth: Some(2353),
+            radio_id: Some(4066),
+            sensor_type: Some(1),
         };
 
-        let log_line = "20/08/2019 15:40:42, 1566315642, Sensor 0, 24.80°C, 3000W\n";
+        let log_line = "{\"v\":2,\"record\":\"reading\",\"timestamp\":1566315642,\"device\":\"CC128-v1.29\",\"sensor\":0,\"temperature\":24.8,\"power\":479,\"channels\":[479],\"device_time\":\"10:27:59\",\"days_since_birth\":2353,\"radio_id\":4066,\"sensor_type\":1}\n";
         assert_eq!(reading.to_log(), log_line);
     }
 }
@@ -0,0 +1,218 @@
+//! Just enough of RFC 2217, the Telnet Com Port Control Option, to set up a
+//! serial port on the far side of a network bridge such as ser2net and read
+//! what the device sends through it.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use crate::source::ByteSource;
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+const BINARY: u8 = 0;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const COM_PORT_OPTION: u8 = 44;
+
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const PARITY_NONE: u8 = 1;
+const STOPSIZE_ONE: u8 = 1;
+
+/// A connection to an RFC 2217 server, which reads as the bytes from the
+/// serial port with the Telnet commands around them taken out.
+pub struct Rfc2217<S> {
+    stream: S,
+    name: String,
+    decoder: Decoder,
+}
+
+impl Rfc2217<TcpStream> {
+    /// Sets up the serial port behind `stream` to run at `bit_rate`, 8N1.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the commands can't be sent.
+    pub fn new(mut stream: TcpStream, bit_rate: u32) -> io::Result<Self> {
+        stream.write_all(&setup_commands(bit_rate))?;
+        let name = match stream.peer_addr() {
+            Ok(address) => format!("rfc2217://{address}"),
+            Err(_) => String::from("RFC 2217"),
+        };
+        Ok(Self {
+            stream,
+            name,
+            decoder: Decoder::default(),
+        })
+    }
+}
+
+impl<S: Read + Write> Read for Rfc2217<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut received = vec![0; buf.len()];
+        // a read of nothing but commands isn't the end of the stream
+        loop {
+            let length = self.stream.read(&mut received)?;
+            if length == 0 {
+                return Ok(0);
+            }
+
+            let mut data = Vec::new();
+            let replies = self.decoder.decode(&received[..length], &mut data);
+            if !replies.is_empty() {
+                self.stream.write_all(&replies)?;
+            }
+            if !data.is_empty() {
+                buf[..data.len()].copy_from_slice(&data);
+                return Ok(data.len());
+            }
+        }
+    }
+}
+
+impl<S: Read + Write> ByteSource for Rfc2217<S> {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+/// Asks to send binary, without go-aheads, and to control the com port,
+/// then sets it to `bit_rate`, 8 data bits, no parity and 1 stop bit.
+fn setup_commands(bit_rate: u32) -> Vec<u8> {
+    let mut commands = vec![
+        IAC,
+        WILL,
+        BINARY,
+        IAC,
+        DO,
+        BINARY,
+        IAC,
+        DO,
+        SUPPRESS_GO_AHEAD,
+        IAC,
+        WILL,
+        COM_PORT_OPTION,
+    ];
+    let mut set = |command, value: &[u8]| {
+        commands.extend_from_slice(&[IAC, SB, COM_PORT_OPTION, command]);
+        for &byte in value {
+            commands.push(byte);
+            // a 255 in the value is sent twice so it isn't taken for IAC
+            if byte == IAC {
+                commands.push(IAC);
+            }
+        }
+        commands.extend_from_slice(&[IAC, SE]);
+    };
+    set(SET_BAUDRATE, &bit_rate.to_be_bytes());
+    set(SET_DATASIZE, &[8]);
+    set(SET_PARITY, &[PARITY_NONE]);
+    set(SET_STOPSIZE, &[STOPSIZE_ONE]);
+    commands
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Data,
+    Command,
+    Negotiation(u8),
+    Subnegotiation,
+    SubnegotiationCommand,
+}
+
+/// Separates the data in a Telnet stream from the commands in it, which can
+/// be split across reads.
+struct Decoder {
+    state: State,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self { state: State::Data }
+    }
+}
+
+impl Decoder {
+    /// Adds the data in `received` to `data`, and returns the replies to send
+    /// to the commands in it.
+    fn decode(&mut self, received: &[u8], data: &mut Vec<u8>) -> Vec<u8> {
+        let mut replies = Vec::new();
+        for &byte in received {
+            self.state = match (self.state, byte) {
+                (State::Data, IAC) => State::Command,
+                (State::Data, _) => {
+                    data.push(byte);
+                    State::Data
+                }
+                (State::Command, IAC) => {
+                    data.push(IAC);
+                    State::Data
+                }
+                (State::Command, SB) => State::Subnegotiation,
+                (State::Command, WILL | WONT | DO | DONT) => State::Negotiation(byte),
+                // anything else, e.g. a no-op, has no option to follow it
+                (State::Command, _) => State::Data,
+                (State::Negotiation(command), option) => {
+                    replies.extend_from_slice(&reply(command, option));
+                    State::Data
+                }
+                // replies to the com port settings aren't needed
+                (State::Subnegotiation, IAC) => State::SubnegotiationCommand,
+                (State::Subnegotiation, _) => State::Subnegotiation,
+                (State::SubnegotiationCommand, SE) => State::Data,
+                (State::SubnegotiationCommand, _) => State::Subnegotiation,
+            };
+        }
+        replies
+    }
+}
+
+/// Refuses the options the server asks for that weren't asked for by
+/// `setup_commands`; the rest are replies to it and need no answer.
+fn reply(command: u8, option: u8) -> Vec<u8> {
+    match (command, option) {
+        (DO, BINARY | COM_PORT_OPTION) | (WILL, BINARY | SUPPRESS_GO_AHEAD) => Vec::new(),
+        (DO, _) => vec![IAC, WONT, option],
+        (WILL, _) => vec![IAC, DONT, option],
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rfc2217::{setup_commands, Decoder};
+
+    #[test]
+    fn port_is_set_up_at_the_bit_rate() {
+        let commands = setup_commands(57600);
+        assert_eq!([255, 250, 44, 1, 0, 0, 0xe1, 0, 255, 240], commands[12..22]);
+        // 0x0001_ffff has a byte that has to be escaped
+        let commands = setup_commands(0x0001_ffff);
+        assert_eq!(
+            [255, 250, 44, 1, 0, 1, 255, 255, 255, 255, 255, 240],
+            commands[12..24]
+        );
+    }
+
+    #[test]
+    fn commands_are_taken_out_wherever_reads_split_them() {
+        let stream: &[u8] = b"\xff\xfd\x2c\xff\xfb\x01<msg>\xff\xfa\x2c\x65\x00\x00\xe1\x00\xff\xf0</msg>\xff\xff\r\n";
+        for split in 0..stream.len() {
+            let mut decoder = Decoder::default();
+            let mut data = Vec::new();
+            let mut replies = decoder.decode(&stream[..split], &mut data);
+            replies.extend(decoder.decode(&stream[split..], &mut data));
+
+            assert_eq!(b"<msg></msg>\xff\r\n".to_vec(), data, "split at {split}");
+            // echo (1) is refused; the com port option was asked for
+            assert_eq!(vec![255, 254, 1], replies, "split at {split}");
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! The tables `store` imports into, which `store migrate` creates or brings
+//! up to date by applying the migrations in `migrations/` that the database
+//! hasn't had yet. `store` and the live database sink won't write to a
+//! database whose schema is out of date.
+//!
+//! Importing the same lines again updates the rows already there rather than
+//! adding duplicates, using unique keys on `entries (device, sensor,
+//! datetime)`, `temperatures (device, datetime)`, `impulses (device, sensor,
+//! datetime)` and `history (device, sensor, kind, bucket_start)`. History is
+//! keyed by when its bucket began rather than when it was received, so a
+//! bucket sent again later is stored once. Duplicates let in by older
+//! versions are removed before each key is added, keeping the copy of a
+//! history bucket received last.
+
+/// A change to the database schema, applied once and recorded in the
+/// `schema_version` table.
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    sql: &'static str,
+}
+
+macro_rules! migration {
+    ($version:expr, $name:expr) => {
+        Migration {
+            version: $version,
+            name: $name,
+            sql: include_str!(concat!("../migrations/", $name, ".sql")),
+        }
+    };
+}
+
+/// Every migration, oldest first. The first few only create what's missing,
+/// so they can be run against a database set up before migrations existed.
+pub const MIGRATIONS: &[Migration] = &[
+    migration!(1, "0001_create_entries"),
+    migration!(2, "0002_add_entry_channels_and_device_fields"),
+    migration!(3, "0003_create_history"),
+    migration!(4, "0004_create_impulses"),
+    migration!(5, "0005_create_temperatures"),
+    migration!(6, "0006_add_entry_device_and_unique_keys"),
+    migration!(7, "0007_add_history_and_impulse_device"),
+    migration!(8, "0008_add_history_and_impulse_unique_keys"),
+    migration!(9, "0009_key_history_by_bucket_start"),
+];
+
+/// The version the database will be at once every migration has been applied.
+#[must_use]
+pub fn latest_version() -> i32 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+/// The version of the latest migration applied to the database, or 0 if it
+/// has never been migrated.
+pub fn current_version(client: &mut postgres::Client) -> Result<i32, postgres::Error> {
+    let row = client.query_one(
+        "SELECT to_regclass('schema_version') IS NOT NULL AS migrated",
+        &[],
+    )?;
+    if !row.get::<_, bool>("migrated") {
+        return Ok(0);
+    }
+
+    let row = client.query_one(
+        "SELECT coalesce(max(version), 0) AS version FROM schema_version",
+        &[],
+    )?;
+    Ok(row.get("version"))
+}
+
+/// Applies the migrations the database hasn't had yet, each in its own
+/// transaction, and returns them.
+pub fn migrate(client: &mut postgres::Client) -> Result<Vec<&'static Migration>, postgres::Error> {
+    client.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version integer PRIMARY KEY,
+            name text NOT NULL,
+            applied_at timestamptz NOT NULL DEFAULT now()
+        )",
+    )?;
+
+    let current_version = current_version(client)?;
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > current_version)
+    {
+        let mut transaction = client.transaction()?;
+        transaction.batch_execute(migration.sql)?;
+        transaction.execute(
+            "INSERT INTO schema_version (version, name) VALUES ($1, $2)",
+            &[&migration.version, &migration.name],
+        )?;
+        transaction.commit()?;
+        applied.push(migration);
+    }
+
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schema::{latest_version, migrate, MIGRATIONS};
+    use crate::test_support::scratch_schema;
+
+    #[test]
+    fn migrations_are_numbered_in_order() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(index + 1, migration.version as usize);
+            assert!(migration
+                .name
+                .starts_with(&format!("{:04}_", migration.version)));
+        }
+        assert_eq!(MIGRATIONS.len(), latest_version() as usize);
+    }
+
+    #[test]
+    #[ignore]
+    fn duplicates_are_removed_before_unique_keys_are_added() {
+        let mut scratch = scratch_schema();
+        let client = &mut scratch.client;
+
+        // a database set up by an importer that let duplicates in
+        for migration in &MIGRATIONS[..5] {
+            client.batch_execute(migration.sql).unwrap();
+        }
+        client
+            .batch_execute(
+                "INSERT INTO entries (sensor, datetime, power) VALUES
+                    (0, '2019-08-20 15:40:42+00', 479),
+                    (0, '2019-08-20 15:40:42+00', 479),
+                    (1, '2019-08-20 15:40:42+00', 21),
+                    (0, '2019-08-20 15:40:48+00', 480);
+                INSERT INTO temperatures (device, datetime, temperature) VALUES
+                    ('', '2019-08-20 15:40:42+00', 24.8),
+                    ('', '2019-08-20 15:40:42+00', 24.8);
+                INSERT INTO impulses (sensor, datetime, sensor_type, impulses, impulses_per_unit) VALUES
+                    (9, '2019-08-20 15:40:42+00', 2, 89466, 1000),
+                    (9, '2019-08-20 15:40:42+00', 2, 89466, 1000);
+                INSERT INTO history (sensor, datetime, kind, age, kwh) VALUES
+                    (0, '2019-08-20 15:40:42+00', 'h', 4, 1.799),
+                    (0, '2019-08-20 15:40:42+00', 'h', 4, 1.799),
+                    (0, '2019-08-20 15:40:42+00', 'h', 6, 1.553),
+                    (0, '2019-08-20 17:41:00+00', 'h', 6, 1.8);",
+            )
+            .unwrap();
+
+        let applied = migrate(client).map(|applied| applied.len());
+        let count = |client: &mut postgres::Client, table: &str| -> i64 {
+            let query = format!("SELECT count(*) FROM {table}");
+            client.query_one(&query, &[]).unwrap().get(0)
+        };
+        let entries = count(client, "entries");
+        let temperatures = count(client, "temperatures");
+        let impulses = count(client, "impulses");
+        let history = count(client, "history");
+        // the same bucket, sent again two hours later
+        let resent_kwh: f32 = client
+            .query_one(
+                "SELECT kwh FROM history WHERE bucket_start = '2019-08-20 10:00+00'",
+                &[],
+            )
+            .unwrap()
+            .get(0);
+
+        assert_eq!(MIGRATIONS.len(), applied.unwrap());
+        assert_eq!(3, entries);
+        assert_eq!(1, temperatures);
+        assert_eq!(1, impulses);
+        assert_eq!(2, history);
+        assert_eq!(1.8, resent_kwh);
+    }
+}
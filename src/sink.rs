@@ -0,0 +1,336 @@
+//! `connect`'s live database sink, which inserts readings as they arrive
+//! rather than waiting for `store` to import the data log.
+
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::config::DatabaseConfig;
+use crate::datalog::parse_log;
+use crate::entries::insert_lines;
+use crate::schema;
+
+/// How long to wait after failing to reach the database before trying again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Inserts data log lines into the database, keeping them in a buffer file
+/// while it can't be reached and replaying them, in order, once it can.
+/// Lines the database refuses, e.g. because of a value it can't store, are
+/// moved to `<buffer>.rejected` rather than holding up the rest.
+///
+/// The inserting is done on a thread of its own, so waiting for the database
+/// doesn't hold up reading from the device.
+pub struct DatabaseSink {
+    sender: Option<Sender<String>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl DatabaseSink {
+    /// A sink that connects on the first line written to it, replaying
+    /// anything left in `buffer_path` by an earlier run first.
+    #[must_use]
+    pub fn new(database: DatabaseConfig, buffer_path: impl Into<PathBuf>) -> Self {
+        let buffer_path = buffer_path.into();
+        let mut rejected_path = OsString::from(&buffer_path);
+        rejected_path.push(".rejected");
+        let mut worker = Worker {
+            database,
+            client: None,
+            buffer_path,
+            rejected_path: PathBuf::from(rejected_path),
+            retry_at: Instant::now(),
+        };
+        let (sender, receiver) = mpsc::channel::<String>();
+        let worker = thread::spawn(move || {
+            for line in receiver {
+                worker.write(&line);
+            }
+        });
+        Self {
+            sender: Some(sender),
+            worker: Some(worker),
+        }
+    }
+
+    /// Queues a reading's line of the data log to be inserted.
+    pub fn write(&self, line: &str) {
+        let sent = self
+            .sender
+            .as_ref()
+            .map(|sender| sender.send(line.to_owned()));
+        if !matches!(sent, Some(Ok(()))) {
+            log::error!("The database sink has stopped, dropping reading");
+        }
+    }
+}
+
+impl Drop for DatabaseSink {
+    /// Waits for the lines already written to be inserted or buffered.
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                log::error!("The database sink stopped unexpectedly");
+            }
+        }
+    }
+}
+
+/// The state of the sink's thread.
+struct Worker {
+    database: DatabaseConfig,
+    client: Option<postgres::Client>,
+    buffer_path: PathBuf,
+    rejected_path: PathBuf,
+    retry_at: Instant,
+}
+
+impl Worker {
+    /// Inserts a reading's line of the data log, or buffers it if the
+    /// database can't be reached or something else goes wrong that trying
+    /// again later might fix.
+    fn write(&mut self, line: &str) {
+        if self.client.is_none() && Instant::now() < self.retry_at {
+            self.buffer(line);
+            return;
+        }
+
+        if let Err(err) = self.insert(line) {
+            log::warn!(
+                "Couldn't write to the database, keeping readings in {} until it's back: {err}",
+                self.buffer_path.display()
+            );
+            self.client = None;
+            self.retry_at = Instant::now() + RETRY_INTERVAL;
+            self.buffer(line);
+        }
+    }
+
+    fn insert(&mut self, line: &str) -> Result<(), Box<dyn Error>> {
+        if self.client.is_none() {
+            self.client = Some(self.connect()?);
+        }
+        let client = self.client.as_mut().expect("connected above");
+        replay(
+            client,
+            &self.buffer_path,
+            &self.rejected_path,
+            self.database.batch_size(),
+        )?;
+        insert_or_reject(client, &[line.to_owned()], &self.rejected_path)?;
+        Ok(())
+    }
+
+    fn connect(&self) -> Result<postgres::Client, Box<dyn Error>> {
+        let mut client = crate::connect(&self.database)?;
+        if schema::current_version(&mut client)? < schema::latest_version() {
+            return Err("the database schema is out of date, run store migrate".into());
+        }
+        log::info!("Connected to the database");
+        Ok(client)
+    }
+
+    fn buffer(&self, line: &str) {
+        let result = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.buffer_path)
+            .and_then(|mut buffer| buffer.write_all(line.as_bytes()));
+        if let Err(err) = result {
+            log::error!(
+                "Couldn't write to {}, dropping reading: {err}",
+                self.buffer_path.display()
+            );
+        }
+    }
+}
+
+/// Inserts the lines buffered while the database couldn't be reached,
+/// `batch_size` at a time, then removes the buffer. If this fails part way,
+/// e.g. because the database goes away again, the lines already inserted are
+/// inserted again next time, which updates them rather than adding
+/// duplicates.
+fn replay(
+    client: &mut postgres::Client,
+    buffer_path: &Path,
+    rejected_path: &Path,
+    batch_size: usize,
+) -> Result<(), Box<dyn Error>> {
+    let buffer = match File::open(buffer_path) {
+        Ok(buffer) => buffer,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut line_count = 0;
+    // lines that aren't UTF-8 are kept, to be logged and skipped as invalid
+    let mut lines = BufReader::new(buffer)
+        .split(b'\n')
+        .map(|line| line.map(|line| String::from_utf8_lossy(&line).into_owned()));
+    loop {
+        let batch = lines
+            .by_ref()
+            .take(batch_size)
+            .collect::<io::Result<Vec<_>>>()?;
+        if batch.is_empty() {
+            break;
+        }
+        line_count += insert_or_reject(client, &batch, rejected_path)?;
+    }
+    fs::remove_file(buffer_path)?;
+    log::info!("Replayed {line_count} buffered readings into the database");
+
+    Ok(())
+}
+
+/// Inserts the readings in `lines`, or if the database refuses them, each
+/// one on its own, moving those it refuses to `rejected_path`. Returns how
+/// many were inserted.
+fn insert_or_reject(
+    client: &mut postgres::Client,
+    lines: &[String],
+    rejected_path: &Path,
+) -> Result<usize, Box<dyn Error>> {
+    let readings = parse_log(lines.iter().map(String::as_str).collect()).readings;
+    let reading_count = readings.len();
+    match insert_lines(client, readings) {
+        Ok(()) => return Ok(reading_count),
+        Err(err) if lines.len() > 1 && is_refused(err.as_ref()) => (),
+        Err(err) if is_refused(err.as_ref()) => {
+            reject(rejected_path, &lines[0], err.as_ref());
+            return Ok(0);
+        }
+        Err(err) => return Err(err),
+    }
+
+    let mut inserted = 0;
+    for line in lines {
+        inserted += insert_or_reject(client, std::slice::from_ref(line), rejected_path)?;
+    }
+    Ok(inserted)
+}
+
+/// Whether `err` is the database refusing the data itself, which trying
+/// again won't fix: a data exception or integrity constraint violation.
+fn is_refused(err: &(dyn Error + 'static)) -> bool {
+    err.downcast_ref::<postgres::Error>()
+        .and_then(postgres::Error::code)
+        .is_some_and(|code| code.code().starts_with("22") || code.code().starts_with("23"))
+}
+
+fn reject(rejected_path: &Path, line: &str, err: &dyn Error) {
+    log::warn!(
+        "The database refused a reading, moving it to {}: {err}: {}",
+        rejected_path.display(),
+        line.trim_end()
+    );
+    let result = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(rejected_path)
+        .and_then(|mut rejected| writeln!(rejected, "{}", line.trim_end()));
+    if let Err(err) = result {
+        log::error!(
+            "Couldn't write to {}, dropping reading: {err}",
+            rejected_path.display()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schema::migrate;
+    use crate::sink::replay;
+    use crate::test_support::scratch_schema;
+    use std::{env, fs};
+
+    #[test]
+    #[ignore]
+    fn buffered_readings_seen_twice_are_replayed_once() {
+        let mut scratch = scratch_schema();
+        let client = &mut scratch.client;
+        migrate(client).unwrap();
+
+        // enough lines to be copied rather than inserted, each there twice
+        let mut buffer = String::new();
+        for second in 0..100 {
+            let timestamp = 1566315642 + second;
+            for power in [479, 480] {
+                buffer.push_str(&format!(
+                    "{{\"v\":2,\"record\":\"reading\",\"timestamp\":{timestamp},\"device\":\"CC128-v1.29\",\"sensor\":0,\"temperature\":24.8,\"power\":{power},\"channels\":[{power}]}}\n"
+                ));
+            }
+        }
+        let buffer_path =
+            env::temp_dir().join(format!("currentcost-sink-test-{}", std::process::id()));
+        fs::write(&buffer_path, buffer).unwrap();
+
+        let rejected_path = buffer_path.with_extension("rejected");
+        let replayed =
+            replay(client, &buffer_path, &rejected_path, 10_000).map_err(|err| err.to_string());
+        let powers: Vec<i32> = client
+            .query("SELECT DISTINCT power FROM entries", &[])
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        let entries: i64 = client
+            .query_one("SELECT count(*) FROM entries", &[])
+            .unwrap()
+            .get(0);
+
+        assert_eq!(Ok(()), replayed);
+        assert!(!buffer_path.exists());
+        assert_eq!(100, entries);
+        assert_eq!(vec![480], powers);
+        assert!(!rejected_path.exists());
+    }
+
+    #[test]
+    #[ignore]
+    fn refused_readings_are_moved_aside() {
+        let mut scratch = scratch_schema();
+        let client = &mut scratch.client;
+        migrate(client).unwrap();
+
+        // PostgreSQL can't store a NUL in text, so one device name is refused
+        let mut buffer = String::new();
+        for second in 0..150 {
+            let timestamp = 1566315642 + second;
+            let device = if second == 70 {
+                "CC128\\u0000"
+            } else {
+                "CC128-v1.29"
+            };
+            buffer.push_str(&format!(
+                "{{\"v\":2,\"record\":\"reading\",\"timestamp\":{timestamp},\"device\":\"{device}\",\"sensor\":0,\"temperature\":24.8,\"power\":479,\"channels\":[479]}}\n"
+            ));
+        }
+        let buffer_path = env::temp_dir().join(format!(
+            "currentcost-sink-refused-test-{}",
+            std::process::id()
+        ));
+        let rejected_path = buffer_path.with_extension("rejected");
+        fs::write(&buffer_path, buffer).unwrap();
+
+        let replayed =
+            replay(client, &buffer_path, &rejected_path, 100).map_err(|err| err.to_string());
+        let entries: i64 = client
+            .query_one("SELECT count(*) FROM entries", &[])
+            .unwrap()
+            .get(0);
+        let rejected = fs::read_to_string(&rejected_path).unwrap();
+        fs::remove_file(&rejected_path).unwrap();
+
+        assert_eq!(Ok(()), replayed);
+        assert!(!buffer_path.exists());
+        assert_eq!(149, entries);
+        assert_eq!(1, rejected.lines().count());
+        assert!(rejected.contains("\"timestamp\":1566315712"));
+    }
+}
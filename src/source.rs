@@ -0,0 +1,218 @@
+//! Where `connect` reads what a device sends from: a serial port, a TCP
+//! connection, or a capture of what one sent earlier, replayed from a file or
+//! stdin.
+//!
+//! A replay can be of a capture written by `connect`, or of raw output saved
+//! some other way, e.g. with `cat /dev/ttyUSB0`, whose lines are dated when
+//! they're read. With `--paced`, each message is replayed as long after the
+//! one before as it was sent, up to a minute.
+
+use std::io::{self, BufRead, Read};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveTime, Utc};
+
+use crate::capture::parse_capture_line;
+
+/// Bytes sent by a device, as they arrive. Reads that time out fail with
+/// `io::ErrorKind::TimedOut`, and a read of 0 bytes means there won't be any
+/// more.
+pub trait ByteSource: Read {
+    /// What the bytes are being read from, for logging.
+    fn name(&self) -> String;
+
+    /// When the bytes last read were originally received, if they're being
+    /// replayed from a capture.
+    fn received_at(&self) -> Option<DateTime<Utc>> {
+        None
+    }
+}
+
+impl ByteSource for Box<dyn serialport::SerialPort> {
+    fn name(&self) -> String {
+        serialport::SerialPort::name(self.as_ref()).unwrap_or_default()
+    }
+}
+
+impl ByteSource for TcpStream {
+    fn name(&self) -> String {
+        self.peer_addr()
+            .map_or_else(|_| String::from("TCP"), |address| address.to_string())
+    }
+}
+
+/// Readings further apart than this in a capture are replayed a minute apart,
+/// so a gap in it, e.g. while the device was unplugged, isn't waited out.
+const MAX_PACING_DELAY: Duration = Duration::from_secs(60);
+
+/// Raw output captured from a device, read back a line at a time, either as
+/// fast as it can be or paced to be as far apart as they were when they were
+/// sent, going by when a capture file says they were received or else the
+/// `<time>` in each message.
+pub struct Replay<R> {
+    reader: R,
+    name: String,
+    paced: bool,
+    line: Vec<u8>,
+    position: usize,
+    received: Option<DateTime<Utc>>,
+    last_time: Option<NaiveTime>,
+}
+
+impl<R: BufRead> Replay<R> {
+    #[must_use]
+    pub fn new(reader: R, name: &str, paced: bool) -> Self {
+        Self {
+            reader,
+            name: String::from(name),
+            paced,
+            line: Vec::new(),
+            position: 0,
+            received: None,
+            last_time: None,
+        }
+    }
+}
+
+impl<R: BufRead> Read for Replay<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.line.len() {
+            self.line.clear();
+            self.position = 0;
+            if self.reader.read_until(b'\n', &mut self.line)? == 0 {
+                return Ok(0);
+            }
+            if let Some((received, bytes)) = parse_capture_line(&self.line) {
+                self.received = Some(received);
+                self.line = bytes;
+            } else {
+                // a line of raw output, which a capture's times don't apply to
+                self.received = None;
+            }
+            if self.paced {
+                let time = self
+                    .received
+                    .map(|received| received.time())
+                    .or_else(|| device_time(&self.line));
+                if let Some(delay) = pacing_delay(self.last_time, time) {
+                    thread::sleep(delay);
+                }
+                self.last_time = time.or(self.last_time);
+            }
+        }
+
+        let remaining = &self.line[self.position..];
+        let length = remaining.len().min(buf.len());
+        buf[..length].copy_from_slice(&remaining[..length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+impl<R: BufRead> ByteSource for Replay<R> {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn received_at(&self) -> Option<DateTime<Utc>> {
+        self.received
+    }
+}
+
+/// The device's clock as given in a message's `<time>` element.
+fn device_time(message: &[u8]) -> Option<NaiveTime> {
+    let message = String::from_utf8_lossy(message);
+    let start = message.find("<time>")? + "<time>".len();
+    let end = start + message[start..].find("</time>")?;
+    NaiveTime::parse_from_str(&message[start..end], "%H:%M:%S").ok()
+}
+
+/// How long to wait between replaying messages sent at `previous` and
+/// `next`, which may be either side of midnight.
+fn pacing_delay(previous: Option<NaiveTime>, next: Option<NaiveTime>) -> Option<Duration> {
+    let mut gap = next? - previous?;
+    if gap < chrono::Duration::zero() {
+        gap += chrono::Duration::days(1);
+    }
+    gap.to_std().ok().map(|gap| gap.min(MAX_PACING_DELAY))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::source::{device_time, pacing_delay, ByteSource, Replay};
+    use chrono::prelude::*;
+    use std::io::Read;
+    use std::time::Duration;
+
+    #[test]
+    fn replay_reads_back_the_capture() {
+        let capture = "<msg><time>10:27:59</time></msg>\r\n<msg><time>10:28:05</time></msg>\r\n";
+        let mut replay = Replay::new(capture.as_bytes(), "capture.log", false);
+        let mut bytes = Vec::new();
+        let mut buf = [0; 7];
+        loop {
+            match replay.read(&mut buf).unwrap() {
+                0 => break,
+                length => bytes.extend_from_slice(&buf[..length]),
+            }
+        }
+
+        assert_eq!(capture.as_bytes(), bytes.as_slice());
+        assert_eq!("capture.log", replay.name());
+        assert_eq!(None, replay.received_at());
+    }
+
+    #[test]
+    fn capture_files_are_replayed_as_received() {
+        let capture = "2019-08-20T15:40:42.100Z\t<msg><time>10:27:59</ti
+2019-08-20T15:40:42.125Z\tme></msg>\\r\\n
+";
+        let mut replay = Replay::new(capture.as_bytes(), "capture.log", false);
+        let mut bytes = String::new();
+        replay.read_to_string(&mut bytes).unwrap();
+
+        assert_eq!("<msg><time>10:27:59</time></msg>\r\n", bytes);
+        let received = Utc.with_ymd_and_hms(2019, 8, 20, 15, 40, 42).unwrap();
+        assert_eq!(
+            Some(received + chrono::Duration::milliseconds(125)),
+            replay.received_at()
+        );
+    }
+
+    #[test]
+    fn raw_lines_after_a_capture_are_not_dated() {
+        let capture = "2019-08-20T15:40:42.125Z\t<msg><time>10:27:59</time></msg>\\r\\n
+<msg><time>10:28:05</time></msg>\r\n";
+        let mut replay = Replay::new(capture.as_bytes(), "capture.log", false);
+        let mut buf = [0; 1000];
+
+        let length = replay.read(&mut buf).unwrap();
+        assert_eq!(b"<msg><time>10:27:59</time></msg>\r\n", &buf[..length]);
+        assert!(replay.received_at().is_some());
+        let length = replay.read(&mut buf).unwrap();
+        assert_eq!(b"<msg><time>10:28:05</time></msg>\r\n", &buf[..length]);
+        assert_eq!(None, replay.received_at());
+    }
+
+    #[test]
+    fn replays_are_paced_by_the_device_clock() {
+        let time = |hour, min, sec| NaiveTime::from_hms_opt(hour, min, sec);
+        assert_eq!(
+            time(10, 27, 59),
+            device_time(b"<msg><src>CC128-v1.29</src><time>10:27:59</time></msg>\r\n")
+        );
+        assert_eq!(None, device_time(b"<msg><src>CC128-v1.29</src></msg>"));
+
+        let delay = |previous, next| pacing_delay(previous, next).map(|delay| delay.as_secs());
+        assert_eq!(Some(6), delay(time(10, 27, 59), time(10, 28, 5)));
+        assert_eq!(Some(6), delay(time(23, 59, 57), time(0, 0, 3)));
+        assert_eq!(Some(60), delay(time(10, 27, 59), time(14, 0, 0)));
+        assert_eq!(None, delay(None, time(10, 27, 59)));
+        assert_eq!(
+            Some(Duration::ZERO),
+            pacing_delay(time(9, 0, 0), time(9, 0, 0))
+        );
+    }
+}
@@ -8,33 +8,155 @@ extern crate fern;
 
 use fern::colors::{Color, ColoredLevelConfig};
 
-use std::env;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::error::Error;
-use std::fs;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
 use std::process;
+use std::time::Instant;
 
+use currentcost::config;
+use currentcost::datalog::LogBatches;
+use currentcost::entries::{format_unixtime, insert_lines};
 use currentcost::get_db_connection;
+use currentcost::schema;
 use currentcost::Config;
 use currentcost::CurrentcostLine;
+use currentcost::HistoryLine;
+use currentcost::ImpulseLine;
+
+/// Imports the data log written by `connect` into PostgreSQL.
+#[derive(Parser)]
+#[command(name = "store", version, about)]
+struct Cli {
+    /// Config file to use instead of the one that's looked for
+    #[arg(long, global = true, value_name = "PATH", long_help = config::CONFIG_HELP)]
+    config: Option<PathBuf>,
+
+    /// Log each batch of lines as it's imported
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// Check the config file, report any problems with it and exit
+    #[arg(long)]
+    check_config: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Import data or history logs written by connect, in either format
+    Import {
+        /// Logs to import
+        #[arg(required = true, value_name = "FILE")]
+        files: Vec<PathBuf>,
+
+        /// Read the logs and report what would be imported, without writing
+        /// to the database
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Import everything after TIME (a Unix timestamp, an RFC 3339 time or
+        /// a date), rather than only what's newer than the database
+        #[arg(long, value_name = "TIME", value_parser = parse_since)]
+        since: Option<i32>,
+    },
+    /// Create the database tables, or bring them up to date
+    Migrate,
+    /// Show the schema version and the latest entries in the database
+    Status,
+}
+
+/// Exit codes, besides 0 for success and 2 for an invalid command line.
+const EXIT_FAILURE: i32 = 1;
+const EXIT_SCHEMA_OUT_OF_DATE: i32 = 3;
+
+/// Returned when the database needs `store migrate` before it can be used.
+#[derive(Debug)]
+struct SchemaOutOfDate;
+
+impl fmt::Display for SchemaOutOfDate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Database schema is out of date, run `store migrate` first"
+        )
+    }
+}
+
+impl Error for SchemaOutOfDate {}
 
 fn main() {
-    let logger_result = setup_logger();
+    let cli = Cli::parse();
+    let level = if cli.verbose {
+        log::LevelFilter::Debug
+    } else {
+        log::LevelFilter::Info
+    };
+    let logger_result = setup_logger(level);
     assert!(logger_result.is_ok(), "Error applying fern logger");
 
-    let args: Vec<String> = env::args().collect();
-    let config = Config::new(&args).unwrap_or_else(|err| {
-        error!("Problem parsing arguments: {err}");
-        process::exit(1);
+    let path = config::find(cli.config.as_deref());
+    let config = Config::load(path.as_deref()).unwrap_or_else(|err| {
+        error!("{err}");
+        process::exit(EXIT_FAILURE);
     });
+    if cli.check_config {
+        info!("Config from {} is valid", config::describe(path.as_deref()));
+        return;
+    }
 
-    if let Err(e) = run(&config) {
+    let result = match &cli.command {
+        Some(Command::Import {
+            files,
+            dry_run,
+            since,
+        }) => run(&config, files, *dry_run, *since),
+        Some(Command::Migrate) => run_migrations(&config),
+        Some(Command::Status) => show_status(&config),
+        None => Cli::command()
+            .error(
+                ErrorKind::MissingSubcommand,
+                "a command is needed unless --check-config is given",
+            )
+            .exit(),
+    };
+    if let Err(e) = result {
         error!("Application error: {e}");
 
-        process::exit(1);
+        if e.is::<SchemaOutOfDate>() {
+            process::exit(EXIT_SCHEMA_OUT_OF_DATE);
+        }
+        process::exit(EXIT_FAILURE);
     }
 }
 
-fn setup_logger() -> Result<(), fern::InitError> {
+/// Parses the time given to `--since` into a Unix timestamp.
+fn parse_since(time: &str) -> Result<i32, String> {
+    let timestamp = if let Ok(timestamp) = time.parse::<i64>() {
+        timestamp
+    } else if let Ok(datetime) = DateTime::parse_from_rfc3339(time) {
+        datetime.timestamp()
+    } else if let Ok(date) = NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        date.and_time(NaiveTime::MIN).and_utc().timestamp()
+    } else {
+        return Err(String::from(
+            "expected a Unix timestamp, an RFC 3339 time or a YYYY-MM-DD date",
+        ));
+    };
+
+    i32::try_from(timestamp).map_err(|_err| String::from("time is out of range"))
+}
+
+fn setup_logger(level: log::LevelFilter) -> Result<(), fern::InitError> {
     let colors_line = ColoredLevelConfig::new()
         .error(Color::Red)
         .warn(Color::Yellow)
@@ -59,163 +181,370 @@ fn setup_logger() -> Result<(), fern::InitError> {
             ));
         })
         // Add blanket level filter -
-        .level(log::LevelFilter::Debug)
+        .level(level)
         .level_for("tokio_reactor", log::LevelFilter::Off)
         .level_for("tokio_postgres", log::LevelFilter::Off)
         .chain(std::io::stdout())
         .apply()?;
     Ok(())
 }
-fn format_unixtime(timestamp: i32) -> DateTime<Utc> {
-    DateTime::from_timestamp(i64::from(timestamp), 0).unwrap()
+fn run_migrations(config: &Config) -> Result<(), Box<dyn Error>> {
+    if !config.database.use_database() {
+        return Err("ignore_db is set, so there's no database to migrate".into());
+    }
+    let mut db = get_db_connection(config);
+    let applied = schema::migrate(&mut db)?;
+    for migration in &applied {
+        info!("Applied migration {}", migration.name);
+    }
+    info!("Database schema is at version {}", schema::latest_version());
+
+    Ok(())
 }
 
-fn run(config: &Config) -> Result<(), Box<dyn Error>> {
-    let last_entry = if config.database.use_database() {
-        let mut db = get_db_connection(config);
+fn show_status(config: &Config) -> Result<(), Box<dyn Error>> {
+    if !config.database.use_database() {
+        println!("ignore_db is set, so nothing is imported into a database");
+        return Ok(());
+    }
+    let mut db = get_db_connection(config);
+
+    let version = schema::current_version(&mut db)?;
+    println!(
+        "Schema version: {version} (latest is {})",
+        schema::latest_version()
+    );
+    if version < schema::latest_version() {
+        return Err(SchemaOutOfDate.into());
+    }
+
+    let last_entries = LastEntries::new(&mut db);
+    for (kind, marks) in last_entries.by_kind() {
+        let mut sensors: Vec<_> = marks.iter().collect();
+        sensors.sort();
+        for ((device, sensor), last_entry) in sensors {
+            println!(
+                "Latest {kind} for sensor {sensor} of {device:?}: {}",
+                format_unixtime(*last_entry)
+            );
+        }
+    }
+
+    Ok(())
+}
 
-        get_latest_timestamp_in_db(&mut db)
+fn run(
+    config: &Config,
+    filenames: &[PathBuf],
+    dry_run: bool,
+    since: Option<i32>,
+) -> Result<(), Box<dyn Error>> {
+    let mut db = if config.database.use_database() {
+        Some(get_db_connection(config))
     } else {
-        0
+        None
     };
+    if let Some(db) = db.as_mut() {
+        if schema::current_version(db)? < schema::latest_version() {
+            return Err(SchemaOutOfDate.into());
+        }
+    }
 
-    info!("Inserting data since {}", format_unixtime(last_entry));
-    let filtered_lines = parse_and_filter_log(&config.filename, last_entry)?;
-    info!("Lines to insert: {}", filtered_lines.len());
+    let last_entries = match (since, db.as_mut()) {
+        (Some(since), _) => {
+            info!("Inserting everything since {}", format_unixtime(since));
+            LastEntries::since(since)
+        }
+        (None, Some(db)) => {
+            let last_entries = LastEntries::new(db);
+            for (kind, marks) in last_entries.by_kind() {
+                for ((device, sensor), last_entry) in marks {
+                    info!(
+                        "Inserting {kind} for sensor {sensor} of {device:?} since {}",
+                        format_unixtime(*last_entry)
+                    );
+                }
+            }
+            last_entries
+        }
+        (None, None) => LastEntries::default(),
+    };
+    if dry_run {
+        info!("Dry run, nothing will be written to the database");
+        db = None;
+    }
 
-    if config.database.use_database() {
-        let mut db = get_db_connection(config);
-        insert_lines(&mut db, filtered_lines)?;
+    let started = Instant::now();
+    let mut totals = ImportTotals::default();
+    for filename in filenames {
+        let file = File::open(filename)
+            .map_err(|err| format!("Couldn't open {}: {err}", filename.display()))?;
+        for batch in LogBatches::new(BufReader::new(file), config.database.batch_size()) {
+            let batch = batch?;
+            totals.readings += import_readings(db.as_mut(), &last_entries, batch.readings)?;
+            totals.impulses += import_impulses(db.as_mut(), &last_entries, batch.impulses)?;
+            totals.history += import_history(db.as_mut(), &last_entries, batch.history)?;
+        }
+    }
+
+    info!("Lines to insert: {}", totals.readings);
+    info!("Impulse lines to insert: {}", totals.impulses);
+    info!("History lines to insert: {}", totals.history);
+    let elapsed = started.elapsed().as_secs_f64();
+    let line_count = totals.readings + totals.impulses + totals.history;
+    // with a dry run or ignore_db, the lines were only read
+    let done = if db.is_some() { "Imported" } else { "Parsed" };
+    if elapsed > 0.0 {
+        #[allow(clippy::cast_precision_loss)]
+        let lines_per_second = line_count as f64 / elapsed;
+        info!("{done} {line_count} lines in {elapsed:.1}s ({lines_per_second:.0} lines/s)");
+    } else {
+        info!("{done} {line_count} lines");
     }
 
     Ok(())
 }
 
-pub fn get_latest_timestamp_in_db(db_connection: &mut postgres::Client) -> i32 {
+/// The latest entry already in each table for each device and sensor, so
+/// that only newer lines get imported.
+#[derive(Default)]
+struct LastEntries {
+    readings: HighWaterMarks,
+    /// Lines at or before this aren't imported, whichever sensor they're from.
+    since: i32,
+    impulses: HighWaterMarks,
+    history: HighWaterMarks,
+}
+
+impl LastEntries {
+    fn new(db: &mut postgres::Client) -> Self {
+        Self {
+            readings: get_latest_timestamps_in_table(db, "entries"),
+            since: 0,
+            impulses: get_latest_timestamps_in_table(db, "impulses"),
+            history: get_latest_timestamps_in_table(db, "history"),
+        }
+    }
+
+    /// Imports everything after `since`, whatever the database already has.
+    fn since(since: i32) -> Self {
+        Self {
+            since,
+            ..Self::default()
+        }
+    }
+
+    fn by_kind(&self) -> [(&'static str, &HighWaterMarks); 3] {
+        [
+            ("readings", &self.readings),
+            ("impulses", &self.impulses),
+            ("history", &self.history),
+        ]
+    }
+
+    /// Whether a line from `device` and `sensor` at `timestamp` is newer than
+    /// both `since` and the latest entry for them in `marks`.
+    fn is_new(&self, marks: &HighWaterMarks, device: &str, sensor: i32, timestamp: i32) -> bool {
+        let last_entry = marks
+            .get(&(device.to_owned(), sensor))
+            .copied()
+            .unwrap_or(0);
+        timestamp > self.since && timestamp > last_entry
+    }
+}
+
+#[derive(Default)]
+struct ImportTotals {
+    readings: usize,
+    impulses: usize,
+    history: usize,
+}
+
+fn import_readings(
+    db: Option<&mut postgres::Client>,
+    last_entries: &LastEntries,
+    mut lines: Vec<CurrentcostLine>,
+) -> Result<usize, Box<dyn Error>> {
+    lines.retain(|line| line.timestamp > last_entries.since);
+    let filtered_lines = filter_log(lines, &last_entries.readings);
+    let line_count = filtered_lines.len();
+    debug!("Lines to insert: {line_count}");
+
+    if let Some(db) = db {
+        insert_lines(db, filtered_lines)?;
+    }
+
+    Ok(line_count)
+}
+
+fn import_history(
+    db: Option<&mut postgres::Client>,
+    last_entries: &LastEntries,
+    history: Vec<HistoryLine>,
+) -> Result<usize, Box<dyn Error>> {
+    let history = filter_history(history, last_entries);
+    let line_count = history.len();
+    debug!("History lines to insert: {line_count}");
+
+    if let Some(db) = db {
+        insert_history_lines(db, history)?;
+    }
+
+    Ok(line_count)
+}
+
+fn import_impulses(
+    db: Option<&mut postgres::Client>,
+    last_entries: &LastEntries,
+    impulses: Vec<ImpulseLine>,
+) -> Result<usize, Box<dyn Error>> {
+    let impulses = filter_impulses(impulses, last_entries);
+    let line_count = impulses.len();
+    debug!("Impulse lines to insert: {line_count}");
+
+    if let Some(db) = db {
+        insert_impulse_lines(db, impulses)?;
+    }
+
+    Ok(line_count)
+}
+
+/// The timestamp of the latest entry imported for each (device, sensor).
+type HighWaterMarks = HashMap<(String, i32), i32>;
+
+pub fn get_latest_timestamps_in_table(
+    db_connection: &mut postgres::Client,
+    table: &str,
+) -> HighWaterMarks {
     #![allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
-    let query = "SELECT CAST ( EXTRACT(epoch FROM max(datetime)) AS float) AS max FROM entries";
+    let query = format!("SELECT device, sensor, CAST ( EXTRACT(epoch FROM max(datetime)) AS float) AS max FROM {table} GROUP BY device, sensor");
 
-    let mut max_timestamp = 0;
-    for row in db_connection.query(query, &[]).unwrap() {
+    let mut max_timestamps = HighWaterMarks::new();
+    for row in db_connection.query(&query, &[]).unwrap() {
         assert!(!row.is_empty());
         let float_value: f64 = row.get("max");
         assert!(float_value >= 0.0 && float_value < (f64::from(i32::MAX)));
-        max_timestamp = float_value as i32;
+        max_timestamps.insert((row.get("device"), row.get("sensor")), float_value as i32);
     }
 
-    max_timestamp
+    max_timestamps
 }
 
-pub fn parse_and_filter_log(
-    filename: &str,
-    skip_before_timestamp: i32,
-) -> Result<Vec<CurrentcostLine>, Box<dyn Error>> {
-    let contents = fs::read_to_string(filename)?;
-    let to_parse = contents.lines().collect();
-
-    let mut parsed = parse_all_lines(to_parse);
-    if skip_before_timestamp > 0 {
-        parsed.sort();
-        parsed = filter_by_timestamp(parsed, skip_before_timestamp);
-    }
+fn filter_log(
+    mut lines: Vec<CurrentcostLine>,
+    last_entries: &HighWaterMarks,
+) -> Vec<CurrentcostLine> {
+    lines.sort();
+    filter_by_timestamp(lines, last_entries)
+}
 
-    Ok(parsed)
+fn filter_impulses(mut lines: Vec<ImpulseLine>, last_entries: &LastEntries) -> Vec<ImpulseLine> {
+    lines.retain(|line| {
+        last_entries.is_new(
+            &last_entries.impulses,
+            &line.device,
+            line.sensor,
+            line.timestamp,
+        )
+    });
+    lines
 }
 
-fn parse_all_lines(lines: Vec<&str>) -> Vec<CurrentcostLine> {
-    let mut parsed_lines = Vec::new();
-    for line in lines {
-        if let Ok(parsed_line) = parse_line(line) {
-            parsed_lines.push(parsed_line);
-        } else {
-            error!("Skipping invalid line: {line}");
-        }
-    }
-    parsed_lines
+fn filter_history(mut lines: Vec<HistoryLine>, last_entries: &LastEntries) -> Vec<HistoryLine> {
+    lines.retain(|line| {
+        last_entries.is_new(
+            &last_entries.history,
+            &line.device,
+            line.sensor,
+            line.timestamp,
+        )
+    });
+    lines
 }
 
-fn insert_lines(
+fn insert_impulse_lines(
     db_client: &mut postgres::Client,
-    lines: Vec<CurrentcostLine>,
+    lines: Vec<ImpulseLine>,
 ) -> Result<(), Box<dyn Error>> {
     let mut transaction = db_client.transaction()?;
-    let query = "INSERT INTO entries (sensor, datetime, power) VALUES ($1, $2, $3)";
+    let query = "INSERT INTO impulses (device, sensor, datetime, sensor_type, radio_id, impulses, impulses_per_unit, rate) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        ON CONFLICT (device, sensor, datetime) DO UPDATE SET sensor_type = EXCLUDED.sensor_type, radio_id = EXCLUDED.radio_id, impulses = EXCLUDED.impulses, impulses_per_unit = EXCLUDED.impulses_per_unit, rate = EXCLUDED.rate";
     let prep_statement = transaction.prepare(query)?;
     for line in lines {
         let unixtime = Utc.timestamp_opt(i64::from(line.timestamp), 0).unwrap();
-        transaction.execute(&prep_statement, &[&line.sensor, &unixtime, &line.power])?;
+        transaction.execute(
+            &prep_statement,
+            &[
+                &line.device,
+                &line.sensor,
+                &unixtime,
+                &line.sensor_type,
+                &line.radio_id,
+                &line.impulses,
+                &line.impulses_per_unit,
+                &line.rate,
+            ],
+        )?;
     }
 
     transaction.commit()?;
     Ok(())
 }
 
-fn parse_line(line: &str) -> Result<CurrentcostLine, &'static str> {
-    let mut position = 0;
-    let mut timestamp = 0;
-    let mut power = 0;
-    let mut sensor = 0;
-
-    for item in line.split(',') {
-        if position == 1 {
-            let timestamp_string = item.trim();
-            if let Ok(time) = timestamp_string.parse::<i32>() {
-                timestamp = time;
-            } else {
-                return Err("Invalid timestamp");
-            };
-        } else if position == 2 {
-            let sensor_string = item;
-            let start_section = "Sensor ";
-            if let Ok(sns) = sensor_string
-                .split_at(start_section.len())
-                .1
-                .trim()
-                .parse::<i32>()
-            {
-                sensor = sns;
-            } else {
-                return Err("Invalid sensor");
-            };
-        } else if position == 4 {
-            let power_string = item;
-            if let Ok(pwr) = power_string
-                .split_at(power_string.len() - 1)
-                .0
-                .trim()
-                .parse::<i32>()
-            {
-                power = pwr;
-            } else {
-                return Err("Invalid power");
-            };
-        }
-        position += 1;
+fn insert_history_lines(
+    db_client: &mut postgres::Client,
+    lines: Vec<HistoryLine>,
+) -> Result<(), Box<dyn Error>> {
+    let mut transaction = db_client.transaction()?;
+    // the device sends the same buckets again, so they're keyed by when they started
+    let query = "INSERT INTO history (device, sensor, datetime, kind, age, kwh, bucket_start) VALUES ($1, $2, $3, $4, $5, $6, $7)
+        ON CONFLICT (device, sensor, kind, bucket_start) DO UPDATE SET datetime = EXCLUDED.datetime, age = EXCLUDED.age, kwh = EXCLUDED.kwh";
+    let prep_statement = transaction.prepare(query)?;
+    for line in lines {
+        let unixtime = Utc.timestamp_opt(i64::from(line.timestamp), 0).unwrap();
+        transaction.execute(
+            &prep_statement,
+            &[
+                &line.device,
+                &line.sensor,
+                &unixtime,
+                &line.kind.prefix().to_string(),
+                &line.age,
+                &line.kwh,
+                &line.kind.bucket_start(unixtime, line.age),
+            ],
+        )?;
     }
 
-    if position == 5 {
-        Ok(CurrentcostLine {
-            timestamp,
-            sensor,
-            power,
-        })
-    } else {
-        Err("Failed to parse line - not enough pieces")
-    }
+    transaction.commit()?;
+    Ok(())
 }
 
-fn filter_by_timestamp(lines: Vec<CurrentcostLine>, timestamp: i32) -> Vec<CurrentcostLine> {
+/// Keeps the lines after the latest entry already imported for their device
+/// and sensor, dropping repeats of the same sensor's reading; `lines` must be
+/// sorted by timestamp.
+fn filter_by_timestamp(
+    lines: Vec<CurrentcostLine>,
+    last_entries: &HighWaterMarks,
+) -> Vec<CurrentcostLine> {
     let mut new_list = Vec::new();
-    let mut last_timestamp = timestamp;
+    let mut last_timestamp = None;
+    let mut sensors_at_last_timestamp = HashSet::new();
 
     for line in lines.into_iter().rev() {
-        if line.timestamp > timestamp {
-            if line.timestamp != last_timestamp {
-                last_timestamp = line.timestamp;
-                new_list.push(line);
-            }
-        } else {
-            break;
+        let last_entry = last_entries
+            .get(&(line.device.clone(), line.sensor))
+            .copied()
+            .unwrap_or(0);
+        if line.timestamp <= last_entry {
+            continue;
+        }
+        if last_timestamp != Some(line.timestamp) {
+            last_timestamp = Some(line.timestamp);
+            sensors_at_last_timestamp.clear();
+        }
+        if sensors_at_last_timestamp.insert((line.device.clone(), line.sensor)) {
+            new_list.push(line);
         }
     }
     new_list
@@ -223,64 +552,107 @@ fn filter_by_timestamp(lines: Vec<CurrentcostLine>, timestamp: i32) -> Vec<Curre
 
 #[cfg(test)]
 mod tests {
-    use super::format_unixtime;
     use super::filter_by_timestamp;
-    use super::parse_all_lines;
-    use super::parse_line;
+    use super::filter_history;
+    use super::filter_impulses;
+    use super::filter_log;
+    use super::parse_since;
+    use super::LastEntries;
+    use currentcost::datalog::parse_log;
+    use std::collections::HashMap;
 
     #[test]
-    fn line_gets_parsed() {
-        let sample_text = "13/04/2019 20:44:48, 1555188288, Sensor 0, 21.200000°C, 631W";
-        let parsed_result = parse_line(sample_text);
-        let parsed = parsed_result.unwrap();
-
-        assert_eq!(1555188288, parsed.timestamp);
-        assert_eq!(0, parsed.sensor);
-        assert_eq!(631, parsed.power);
-    }
-
-    #[test]
-    fn multilines_get_parsed() {
+    fn lines_get_skipped_if_before_last_run() {
         let sample_text = "14/04/2019 23:25:26, 1555284326, Sensor 1, 22.100000°C, 0W
         14/04/2019 23:25:29, 1555284329, Sensor 0, 22.100000°C, 544W
         14/04/2019 23:25:32, 1555284332, Sensor 1, 22.100000°C, 0W";
-        let parsed = parse_all_lines(sample_text.lines().collect());
+        let parsed = parse_log(sample_text.lines().collect()).readings;
+        let last_entries = HashMap::from([
+            ((String::new(), 0), 1555284331),
+            ((String::new(), 1), 1555284331),
+        ]);
+        let filtered = filter_by_timestamp(parsed, &last_entries);
 
-        assert_eq!(3, parsed.len());
-        assert_eq!(1555284326, parsed[0].timestamp);
-        assert_eq!(1555284329, parsed[1].timestamp);
-        assert_eq!(1555284332, parsed[2].timestamp);
+        assert_eq!(1, filtered.len());
+        assert_eq!(1555284332, filtered[0].timestamp);
     }
 
     #[test]
-    fn empty_string_get_parsed() {
-        let sample_text = "";
-        let parsed = parse_all_lines(sample_text.lines().collect());
-
-        assert_eq!(0, parsed.len());
+    fn lines_get_skipped_per_device_and_sensor() {
+        let sample_text = "{\"v\":2,\"record\":\"reading\",\"timestamp\":1566315642,\"device\":\"CC128-v1.29\",\"sensor\":0,\"temperature\":24.8,\"power\":479,\"channels\":[479]}
+        {\"v\":2,\"record\":\"reading\",\"timestamp\":1566315642,\"device\":\"CC128-v1.29\",\"sensor\":1,\"temperature\":24.8,\"power\":21,\"channels\":[21]}
+        {\"v\":2,\"record\":\"reading\",\"timestamp\":1566315648,\"device\":\"CC128-v1.29\",\"sensor\":0,\"temperature\":24.8,\"power\":480,\"channels\":[480]}
+        {\"v\":2,\"record\":\"reading\",\"timestamp\":1566315642,\"device\":\"CC128-v0.11\",\"sensor\":0,\"temperature\":24.8,\"power\":300,\"channels\":[300]}";
+        let parsed = parse_log(sample_text.lines().collect()).readings;
+        // sensor 1 is behind sensor 0, and the second device has nothing imported yet
+        let last_entries = HashMap::from([
+            ((String::from("CC128-v1.29"), 0), 1566315645),
+            ((String::from("CC128-v1.29"), 1), 1566315600),
+        ]);
+        let filtered = filter_log(parsed, &last_entries);
+
+        assert_eq!(3, filtered.len());
+        assert_eq!((1566315648, 0), (filtered[0].timestamp, filtered[0].sensor));
+        assert_eq!("CC128-v0.11", filtered[1].device);
+        assert_eq!((1566315642, 1), (filtered[2].timestamp, filtered[2].sensor));
     }
 
     #[test]
-    fn invalid_line_gets_dropped() {
-        let sample_text = "14/04/2019 23:25:26, Sensor 1, 22.100000°C, 0W
-        14/04/2019 23:25:29, 1555284329, Sensor 0, 22.100000°C, 544W
-        14/04/2019 23:25:32, 1555284332, Sensor, 22.100000°C, 0W";
-
-        let parsed = parse_all_lines(sample_text.lines().collect());
+    fn impulses_get_skipped_per_device_and_sensor() {
+        let sample_text = "{\"v\":2,\"record\":\"impulse\",\"timestamp\":1566315642,\"device\":\"CC128-v1.29\",\"sensor\":9,\"sensor_type\":2,\"impulses\":89466,\"impulses_per_unit\":1000}
+        {\"v\":2,\"record\":\"impulse\",\"timestamp\":1566315642,\"device\":\"CC128-v1.29\",\"sensor\":8,\"sensor_type\":2,\"impulses\":1200,\"impulses_per_unit\":1000}
+        {\"v\":2,\"record\":\"impulse\",\"timestamp\":1566315642,\"device\":\"CC128-v0.11\",\"sensor\":9,\"sensor_type\":2,\"impulses\":300,\"impulses_per_unit\":1000}";
+        let parsed = parse_log(sample_text.lines().collect()).impulses;
+        // sensor 9 is already ahead, but sensor 8's log arrived late and the
+        // second device's sensor 9 is a different meter
+        let last_entries = LastEntries {
+            impulses: HashMap::from([((String::from("CC128-v1.29"), 9), 1566315700)]),
+            ..LastEntries::default()
+        };
+        let filtered = filter_impulses(parsed, &last_entries);
+
+        assert_eq!(2, filtered.len());
+        assert_eq!(
+            ("CC128-v1.29", 8),
+            (filtered[0].device.as_str(), filtered[0].sensor)
+        );
+        assert_eq!(
+            ("CC128-v0.11", 9),
+            (filtered[1].device.as_str(), filtered[1].sensor)
+        );
+    }
 
-        assert_eq!(1, parsed.len());
+    #[test]
+    fn history_gets_skipped_per_device_and_sensor() {
+        let sample_text = "{\"v\":2,\"record\":\"history\",\"timestamp\":1566315642,\"device\":\"CC128-v1.29\",\"sensor\":1,\"kind\":\"h\",\"age\":4,\"kwh\":1.799}
+        {\"v\":2,\"record\":\"history\",\"timestamp\":1566315642,\"device\":\"CC128-v1.29\",\"sensor\":2,\"kind\":\"h\",\"age\":4,\"kwh\":0.2}
+        {\"v\":2,\"record\":\"history\",\"timestamp\":1566315642,\"device\":\"CC128-v0.11\",\"sensor\":1,\"kind\":\"h\",\"age\":4,\"kwh\":0.5}";
+        let parsed = parse_log(sample_text.lines().collect()).history;
+        let last_entries = LastEntries {
+            history: HashMap::from([((String::from("CC128-v1.29"), 1), 1566315700)]),
+            ..LastEntries::default()
+        };
+        let filtered = filter_history(parsed, &last_entries);
+
+        assert_eq!(2, filtered.len());
+        assert_eq!(
+            ("CC128-v1.29", 2),
+            (filtered[0].device.as_str(), filtered[0].sensor)
+        );
+        assert_eq!(
+            ("CC128-v0.11", 1),
+            (filtered[1].device.as_str(), filtered[1].sensor)
+        );
     }
 
     #[test]
-    fn lines_get_skipped_if_before_last_run() {
-        let sample_text = "14/04/2019 23:25:26, 1555284326, Sensor 1, 22.100000°C, 0W
-        14/04/2019 23:25:29, 1555284329, Sensor 0, 22.100000°C, 544W
-        14/04/2019 23:25:32, 1555284332, Sensor 1, 22.100000°C, 0W";
-        let parsed = parse_all_lines(sample_text.lines().collect());
-        let filtered = filter_by_timestamp(parsed, 1555284331);
+    fn since_applies_to_every_device_and_sensor() {
+        let since = LastEntries::since(1566315642);
 
-        assert_eq!(1, filtered.len());
-        assert_eq!(1555284332, filtered[0].timestamp);
+        assert!(!since.is_new(&since.impulses, "CC128-v1.29", 8, 1566315642));
+        assert!(since.is_new(&since.impulses, "CC128-v1.29", 8, 1566315643));
+        assert!(!since.is_new(&since.history, "CC128-v0.11", 1, 1566315642));
+        assert!(since.is_new(&since.readings, "", 0, 1566315643));
     }
 
     #[test]
@@ -289,19 +661,22 @@ mod tests {
         11/08/2019 21:04:03, 1565557443, Sensor 0, 25.200000°C, 2637W
         11/08/2019 21:04:03, 1565557443, Sensor 1, 25.200000°C, 2637W";
 
-        let parsed = parse_all_lines(sample_text.lines().collect());
-        let filtered = filter_by_timestamp(parsed, 0);
+        let parsed = parse_log(sample_text.lines().collect()).readings;
+        let filtered = filter_by_timestamp(parsed, &HashMap::new());
 
-        assert_eq!(1, filtered.len());
+        assert_eq!(2, filtered.len());
         assert_eq!(1565557443, filtered[0].timestamp);
+        assert_eq!(1, filtered[0].sensor);
+        assert_eq!(1565557443, filtered[1].timestamp);
+        assert_eq!(0, filtered[1].sensor);
     }
 
     #[test]
-    fn max_datetime_formatted_correctly() {
-        let timestamp = 1711972202;
-        let time_string = "2024-04-01 11:50:02 UTC";
-
-        let result = format_unixtime(timestamp);
-        assert_eq!(time_string, result.to_string());
+    fn since_accepts_timestamps_times_and_dates() {
+        assert_eq!(Ok(1711972202), parse_since("1711972202"));
+        assert_eq!(Ok(1711972202), parse_since("2024-04-01T12:50:02+01:00"));
+        assert_eq!(Ok(1711929600), parse_since("2024-04-01"));
+        assert!(parse_since("yesterday").is_err());
+        assert!(parse_since("99999999999").is_err());
     }
 }
@@ -0,0 +1,42 @@
+//! Setup for the tests that need a PostgreSQL database. Those are ignored
+//! unless run with `cargo test -- --ignored`, and connect to the database
+//! given as a connection string in `CURRENTCOST_TEST_DATABASE`, e.g.
+//! `"host=localhost user=postgres"`.
+
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A client whose tables are created in a schema of its own, which is dropped
+/// along with them when it goes out of scope, even if the test fails.
+pub struct ScratchSchema {
+    pub client: postgres::Client,
+    name: String,
+}
+
+/// Connects to the test database and creates an empty schema to work in.
+pub fn scratch_schema() -> ScratchSchema {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let connection_string = env::var("CURRENTCOST_TEST_DATABASE")
+        .expect("CURRENTCOST_TEST_DATABASE should be a connection string");
+    let mut client = postgres::Client::connect(&connection_string, postgres::NoTls).unwrap();
+    // tests run side by side, so each needs a name of its own
+    let name = format!(
+        "test_{}_{}",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    );
+    client
+        .batch_execute(&format!("CREATE SCHEMA {name}; SET search_path TO {name}"))
+        .unwrap();
+    ScratchSchema { client, name }
+}
+
+impl Drop for ScratchSchema {
+    fn drop(&mut self) {
+        let drop_schema = format!("DROP SCHEMA {} CASCADE", self.name);
+        if let Err(err) = self.client.batch_execute(&drop_schema) {
+            eprintln!("Couldn't drop the test schema {}: {err}", self.name);
+        }
+    }
+}